@@ -0,0 +1,107 @@
+//! Benchmark DNS resolution from the in-memory zone cache under parallel load.
+//!
+//! Runs the same query load against stores with different numbers of cache shards. With a
+//! single shard, all lookups contend on one lock.
+//!
+//!     cargo run --release --example bench_resolve
+
+use std::time::Instant;
+
+use anyhow::{ensure, Result};
+use clap::Parser;
+use hickory_proto::rr::{Name, RecordType};
+use iroh_dns_server::store::{
+    InMemoryPacketStore, PacketSource, PublicKeyBytes, ZoneStore, ZoneStoreOptions,
+    DEFAULT_CACHE_SHARDS,
+};
+use iroh_net::{
+    dns::node_info::{NodeInfo, IROH_TXT_NAME},
+    key::SecretKey,
+};
+use pkarr::SignedPacket;
+use url::Url;
+
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Number of zones to publish and resolve.
+    #[clap(long, default_value_t = 10_000)]
+    zones: usize,
+    /// Number of queries per task.
+    #[clap(long, default_value_t = 200_000)]
+    queries: usize,
+    /// Number of concurrent tasks. Defaults to the number of CPUs.
+    #[clap(long)]
+    tasks: Option<usize>,
+    /// Numbers of cache shards to compare.
+    #[clap(long, value_delimiter = ',', default_values_t = [1, DEFAULT_CACHE_SHARDS])]
+    shards: Vec<usize>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Cli::parse();
+    let tasks = match args.tasks {
+        Some(tasks) => tasks,
+        None => std::thread::available_parallelism()?.get(),
+    };
+
+    println!("create {} signed packets ...", args.zones);
+    let relay_url: Url = "https://relay.example.".parse()?;
+    let mut packets = Vec::with_capacity(args.zones);
+    for _ in 0..args.zones {
+        let secret_key = SecretKey::generate();
+        let node_info = NodeInfo::new(secret_key.public(), Some(relay_url.clone()));
+        packets.push(node_info.to_pkarr_signed_packet(&secret_key, 30)?);
+    }
+    let pubkeys: Vec<PublicKeyBytes> = packets
+        .iter()
+        .map(PublicKeyBytes::from_signed_packet)
+        .collect();
+    let name = Name::from_utf8(IROH_TXT_NAME)?;
+
+    for shards in args.shards {
+        let options = ZoneStoreOptions {
+            cache_shards: shards,
+            ..Default::default()
+        };
+        let store = ZoneStore::with_options(InMemoryPacketStore::new(), options)?;
+        let inserts = packets
+            .iter()
+            .map(|packet| SignedPacket::from_bytes(packet.as_bytes().clone(), false))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(|packet| store.insert(packet, PacketSource::PkarrPublish));
+        futures::future::try_join_all(inserts).await?;
+        // Warm up the cache, so that all queries below are cache hits.
+        for pubkey in pubkeys.iter() {
+            let res = store.resolve(pubkey, &name, RecordType::TXT).await?;
+            ensure!(res.is_some(), "failed to resolve {pubkey}");
+        }
+
+        let start = Instant::now();
+        let handles = (0..tasks).map(|task| {
+            let store = store.clone();
+            let pubkeys = pubkeys.clone();
+            let name = name.clone();
+            let queries = args.queries;
+            tokio::task::spawn(async move {
+                for i in 0..queries {
+                    // Spread the tasks over different zones.
+                    let pubkey = &pubkeys[(task * 7919 + i) % pubkeys.len()];
+                    store.resolve(pubkey, &name, RecordType::TXT).await?;
+                }
+                anyhow::Ok(())
+            })
+        });
+        for res in futures::future::join_all(handles).await {
+            res??;
+        }
+        let elapsed = start.elapsed();
+        let total = tasks * args.queries;
+        println!(
+            "shards={shards:<4} tasks={tasks} queries={total} elapsed={elapsed:.2?} qps={:.0}",
+            total as f64 / elapsed.as_secs_f64()
+        );
+    }
+    Ok(())
+}
@@ -1,16 +1,21 @@
 //! Configuration for the server
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::{
     env,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::{Path, PathBuf},
+    time::Duration,
 };
+use url::Url;
 
 use crate::{
     dns::DnsConfig,
-    http::{CertMode, HttpConfig, HttpsConfig},
+    http::{CertMode, HttpConfig, HttpsConfig, DEFAULT_MAX_WATCHERS},
+    replication::{ReplicationOptions, DEFAULT_REPLICATION_INTERVAL},
+    store::{GcOptions, RepublishOptions, ZoneStoreOptions},
+    upstream::ForwardOptions,
 };
 
 const DEFAULT_METRICS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9117);
@@ -38,6 +43,30 @@ pub struct Config {
     /// The metrics server is started by default. To disable the metrics server, set to
     /// `Some(MetricsConfig::disabled())`.
     pub metrics: Option<MetricsConfig>,
+    /// Config for the signed packet store.
+    ///
+    /// If set to `None` the defaults of [`StoreConfig`] are used.
+    pub store: Option<StoreConfig>,
+    /// Config for the mainline DHT.
+    ///
+    /// If set to `None` the DHT is not used.
+    pub mainline: Option<MainlineConfig>,
+    /// Config for forwarding publishes to other pkarr relays.
+    ///
+    /// If set to `None` publishes are not forwarded.
+    pub forward: Option<ForwardConfig>,
+    /// Config for proxying lookups to another pkarr relay.
+    ///
+    /// If set to `None` lookups are only answered from the local store.
+    pub proxy: Option<ProxyConfig>,
+    /// Config for replication between instances.
+    ///
+    /// If set to `None` replication is disabled.
+    pub replication: Option<ReplicationConfig>,
+    /// Config for watching signed packets with server-sent events.
+    ///
+    /// If set to `None` the defaults of [`WatchConfig`] are used.
+    pub watch: Option<WatchConfig>,
 }
 
 /// The config for the metrics server.
@@ -59,6 +88,115 @@ impl MetricsConfig {
     }
 }
 
+/// The config for the mainline DHT.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MainlineConfig {
+    /// Set to true to resolve pubkeys that are not found in the store from the mainline DHT.
+    #[serde(default)]
+    pub enabled: bool,
+    /// DHT nodes to bootstrap from, as `host:port`.
+    ///
+    /// Defaults to the public bootstrap nodes.
+    pub bootstrap: Option<Vec<String>>,
+    /// Set to true to periodically republish all stored packets to the DHT.
+    ///
+    /// This is independent of [`Self::enabled`]. Packets older than the maximum age of the store
+    /// are not republished.
+    pub republish: Option<bool>,
+    /// Interval in seconds between republishing runs. Defaults to one hour.
+    pub republish_interval_secs: Option<u64>,
+}
+
+/// The config for forwarding publishes to other pkarr relays.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ForwardConfig {
+    /// URLs of the pkarr relays to forward accepted publishes to, e.g.
+    /// `https://relay.example/pkarr`.
+    pub relays: Vec<String>,
+    /// Number of retries after a failed forward. Defaults to 5.
+    pub max_retries: Option<u32>,
+    /// Time in milliseconds to wait before the first retry.
+    ///
+    /// The backoff doubles with each retry. Defaults to 1 second.
+    pub initial_backoff_ms: Option<u64>,
+}
+
+/// The config for proxying lookups to another pkarr relay.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    /// URL of the pkarr relay to query for pubkeys that are not found in the local store, e.g.
+    /// `https://relay.example/pkarr`.
+    ///
+    /// Found packets are verified and stored locally.
+    pub relay: String,
+}
+
+/// The config for replication between instances.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ReplicationConfig {
+    /// Shared secret of all instances.
+    ///
+    /// Peers present it to pull packets from this instance, and this instance presents it to
+    /// pull packets from its peers.
+    pub token: String,
+    /// Base URLs of the instances to pull packets from, e.g. `https://peer.example`.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Interval in milliseconds between pulls. Defaults to 5 seconds.
+    pub interval_ms: Option<u64>,
+}
+
+/// The config for watching signed packets at `/pkarr/:key/watch`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct WatchConfig {
+    /// Maximum number of concurrently open watch streams.
+    ///
+    /// Further watch requests are answered with `503 Service Unavailable`. Defaults to 10000.
+    pub max_watchers: Option<usize>,
+}
+
+/// The config for the signed packet store.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StoreConfig {
+    /// Maximum age of a signed packet in seconds, measured from its pkarr timestamp.
+    ///
+    /// If [`Self::gc_enabled`] is set, older packets are evicted by a periodic garbage collection
+    /// task. Defaults to one week.
+    pub max_age_secs: Option<u64>,
+    /// Interval in seconds between garbage collection runs. Defaults to one hour.
+    pub gc_interval_secs: Option<u64>,
+    /// Set to true to evict packets older than [`Self::max_age_secs`].
+    ///
+    /// Disabled by default, i.e. packets are kept until they are replaced.
+    pub gc_enabled: Option<bool>,
+    /// Maximum number of publishes that are committed in a single write transaction.
+    ///
+    /// Defaults to 1024.
+    pub max_batch_size: Option<usize>,
+    /// Maximum time in milliseconds to wait for concurrent publishes to be committed together.
+    ///
+    /// Defaults to 0, i.e. only publishes that queue up while the previous batch is being
+    /// committed are batched, and no latency is added to publishes.
+    pub max_batch_time_ms: Option<u64>,
+    /// Number of packets to keep in the history of each public key.
+    ///
+    /// Defaults to 0, i.e. no history is kept and the history endpoint only returns the current
+    /// packet. Lowering the size trims the stored histories on the next start.
+    pub history_size: Option<usize>,
+    /// Maximum number of zones kept in the in-memory cache used to answer DNS queries.
+    ///
+    /// Defaults to 1048576.
+    pub cache_capacity: Option<usize>,
+    /// Maximum approximate memory usage of the in-memory zone cache in bytes.
+    ///
+    /// Unbounded by default, i.e. only limited by `cache_capacity`.
+    pub cache_max_bytes: Option<usize>,
+    /// Number of independently locked shards of the in-memory zone cache.
+    ///
+    /// Defaults to 64.
+    pub cache_shards: Option<usize>,
+}
+
 impl Config {
     /// Load the config from a file.
     pub async fn load(path: impl AsRef<Path>) -> Result<Config> {
@@ -66,9 +204,26 @@ impl Config {
             .await
             .with_context(|| format!("failed to read {}", path.as_ref().to_string_lossy()))?;
         let config: Config = toml::from_str(&s)?;
+        config.validate()?;
         Ok(config)
     }
 
+    /// Check for combinations of settings that the server can't serve.
+    fn validate(&self) -> Result<()> {
+        // The DNS-over-QUIC listener of hickory only takes a static certificate and key, so it
+        // can't use the certificates that are renewed via ACME.
+        let lets_encrypt = self
+            .https
+            .as_ref()
+            .is_some_and(|c| c.cert_mode == CertMode::LetsEncrypt);
+        ensure!(
+            self.dns.quic.is_none() || !lets_encrypt,
+            "dns.quic is not supported with https.cert_mode = \"lets_encrypt\", \
+             use the \"manual\" or \"self_signed\" cert mode or remove the dns.quic section"
+        );
+        Ok(())
+    }
+
     /// Get the data directory.
     pub fn data_dir() -> Result<PathBuf> {
         let dir = if let Some(val) = env::var_os("IROH_DNS_DATA_DIR") {
@@ -83,6 +238,9 @@ impl Config {
     }
 
     /// Get the path to the store database file.
+    ///
+    /// The `-1` suffix is kept for compatibility with existing deployments. The schema version is
+    /// tracked inside the database, which is migrated on startup.
     pub fn signed_packet_store_path() -> Result<PathBuf> {
         Ok(Self::data_dir()?.join("signed-packets-1.db"))
     }
@@ -97,6 +255,134 @@ impl Config {
             },
         }
     }
+
+    /// Get the options for the [`crate::store::ZoneStore`].
+    pub(crate) fn zone_store_options(&self) -> ZoneStoreOptions {
+        let conf = self.store.as_ref();
+        let mut options = ZoneStoreOptions::default();
+        if let Some(size) = conf.and_then(|c| c.max_batch_size) {
+            options.max_batch_size = size;
+        }
+        if let Some(ms) = conf.and_then(|c| c.max_batch_time_ms) {
+            options.max_batch_time = Duration::from_millis(ms);
+        }
+        if let Some(size) = conf.and_then(|c| c.history_size) {
+            options.history_size = size;
+        }
+        if let Some(capacity) = conf.and_then(|c| c.cache_capacity) {
+            options.cache_capacity = capacity.max(1);
+        }
+        options.cache_max_bytes = conf.and_then(|c| c.cache_max_bytes);
+        if let Some(shards) = conf.and_then(|c| c.cache_shards) {
+            options.cache_shards = shards.max(1);
+        }
+        options
+    }
+
+    /// Get the config for the mainline DHT, if enabled.
+    pub(crate) fn mainline_config(&self) -> Option<&MainlineConfig> {
+        self.mainline.as_ref().filter(|conf| conf.enabled)
+    }
+
+    /// Get the options for republishing stored packets to the DHT, if enabled.
+    pub(crate) fn republish_options(&self) -> Option<RepublishOptions> {
+        let conf = self.mainline.as_ref()?;
+        if !conf.republish.unwrap_or(false) {
+            return None;
+        }
+        let mut options = RepublishOptions::default();
+        if let Some(secs) = self.store.as_ref().and_then(|c| c.max_age_secs) {
+            options.max_age = Duration::from_secs(secs);
+        }
+        if let Some(secs) = conf.republish_interval_secs {
+            options.interval = Duration::from_secs(secs.max(1));
+        }
+        Some(options)
+    }
+
+    /// Get the options for forwarding publishes to other pkarr relays, if any are configured.
+    pub(crate) fn forward_options(&self) -> Result<Option<ForwardOptions>> {
+        let Some(conf) = self.forward.as_ref().filter(|c| !c.relays.is_empty()) else {
+            return Ok(None);
+        };
+        let mut options = ForwardOptions::default();
+        for url in conf.relays.iter() {
+            let url = url
+                .parse()
+                .with_context(|| format!("invalid pkarr relay url: {url}"))?;
+            options.relays.push(url);
+        }
+        if let Some(retries) = conf.max_retries {
+            options.max_retries = retries;
+        }
+        if let Some(ms) = conf.initial_backoff_ms {
+            options.initial_backoff = Duration::from_millis(ms);
+        }
+        Ok(Some(options))
+    }
+
+    /// Get the URL of the pkarr relay to proxy lookups to, if set.
+    pub(crate) fn proxy_relay(&self) -> Result<Option<Url>> {
+        let Some(conf) = self.proxy.as_ref() else {
+            return Ok(None);
+        };
+        let url = conf
+            .relay
+            .parse()
+            .with_context(|| format!("invalid pkarr relay url: {}", conf.relay))?;
+        Ok(Some(url))
+    }
+
+    /// Get the options for replication between instances, if enabled.
+    pub(crate) fn replication_options(&self) -> Result<Option<ReplicationOptions>> {
+        let Some(conf) = self.replication.as_ref() else {
+            return Ok(None);
+        };
+        ensure!(
+            !conf.token.is_empty(),
+            "replication token must not be empty"
+        );
+        let mut peers = Vec::with_capacity(conf.peers.len());
+        for url in conf.peers.iter() {
+            let url = url
+                .parse()
+                .with_context(|| format!("invalid replication peer url: {url}"))?;
+            peers.push(url);
+        }
+        let interval = conf
+            .interval_ms
+            .map(|ms| Duration::from_millis(ms.max(1)))
+            .unwrap_or(DEFAULT_REPLICATION_INTERVAL);
+        Ok(Some(ReplicationOptions {
+            token: conf.token.clone(),
+            peers,
+            interval,
+        }))
+    }
+
+    /// Get the maximum number of concurrently open watch streams.
+    pub(crate) fn max_watchers(&self) -> usize {
+        self.watch
+            .as_ref()
+            .and_then(|c| c.max_watchers)
+            .unwrap_or(DEFAULT_MAX_WATCHERS)
+    }
+
+    /// Get the options for the garbage collection of stale packets, if enabled.
+    pub(crate) fn gc_options(&self) -> Option<GcOptions> {
+        let conf = self.store.as_ref();
+        if !conf.and_then(|c| c.gc_enabled).unwrap_or(false) {
+            return None;
+        }
+        let mut options = GcOptions::default();
+        if let Some(secs) = conf.and_then(|c| c.max_age_secs) {
+            options.max_age = Duration::from_secs(secs);
+        }
+        if let Some(secs) = conf.and_then(|c| c.gc_interval_secs) {
+            options.interval = Duration::from_secs(secs.max(1));
+        }
+        Some(options)
+    }
 }
 
 impl Default for Config {
@@ -126,8 +412,17 @@ impl Default for Config {
                 rr_a: Some(Ipv4Addr::LOCALHOST),
                 rr_aaaa: None,
                 rr_ns: Some("ns1.irohdns.example.".to_string()),
+                dnssec: None,
+                tls: None,
+                quic: None,
             },
             metrics: None,
+            store: None,
+            mainline: None,
+            forward: None,
+            proxy: None,
+            replication: None,
+            watch: None,
         }
     }
 }
@@ -8,7 +8,7 @@ use std::{
     time::Duration,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
 use hickory_server::{
@@ -34,16 +34,24 @@ use tokio::{
     sync::broadcast,
 };
 
-use crate::{metrics::Metrics, store::ZoneStore};
+use crate::{http::TlsMaterial, metrics::Metrics, store::ZoneStore};
 
-use self::node_authority::NodeAuthority;
+use self::node_authority::{NodeAuthority, StaticZone};
 
+pub use self::dnssec::{DnssecConfig, ZoneSigner, ZoneSigners, DEFAULT_SIGNATURE_VALIDITY};
+
+mod dnssec;
 mod node_authority;
 
 const DEFAULT_NS_TTL: u32 = 60 * 60 * 12; // 12h
 const DEFAULT_SOA_TTL: u32 = 60 * 60 * 24 * 14; // 14d
 const DEFAULT_A_TTL: u32 = 60 * 60; // 1h
 
+/// The default port for DNS-over-TLS, see RFC 7858.
+pub const DEFAULT_DOT_PORT: u16 = 853;
+/// The default port for DNS-over-QUIC, see RFC 9250.
+pub const DEFAULT_DOQ_PORT: u16 = 853;
+
 /// DNS server settings
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DnsConfig {
@@ -65,17 +73,68 @@ pub struct DnsConfig {
     pub rr_aaaa: Option<Ipv6Addr>,
     /// `NS` record to set for all origins
     pub rr_ns: Option<String>,
+
+    /// DNSSEC signing keys for the origins.
+    ///
+    /// If set to `None`, responses are not signed.
+    pub dnssec: Option<DnssecConfig>,
+
+    /// DNS-over-TLS listener, using the certificates of the HTTPS server.
+    ///
+    /// If set to `None`, DNS-over-TLS is disabled.
+    pub tls: Option<DnsTlsConfig>,
+
+    /// DNS-over-QUIC listener, using the certificates of the HTTPS server.
+    ///
+    /// Only the `manual` and `self_signed` certificate modes are supported, as the listener can't
+    /// use certificates that are renewed via ACME. If set to `None`, DNS-over-QUIC is disabled.
+    pub quic: Option<DnsQuicConfig>,
+}
+
+/// DNS-over-TLS settings
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DnsTlsConfig {
+    /// The port to serve DNS-over-TLS at. Defaults to 853.
+    pub port: Option<u16>,
+}
+
+/// DNS-over-QUIC settings
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DnsQuicConfig {
+    /// The UDP port to serve DNS-over-QUIC at. Defaults to 853.
+    pub port: Option<u16>,
+}
+
+impl DnsConfig {
+    /// Parse the configured origins.
+    pub fn origin_names(&self) -> Result<Vec<Name>> {
+        let origins = self
+            .origins
+            .iter()
+            .map(Name::from_utf8)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(origins)
+    }
 }
 
 /// A DNS server that serves pkarr signed packets.
 pub struct DnsServer {
     local_addr: SocketAddr,
+    tls_addr: Option<SocketAddr>,
+    quic_addr: Option<SocketAddr>,
     server: hickory_server::ServerFuture<DnsHandler>,
 }
 
 impl DnsServer {
     /// Spawn the server.
-    pub async fn spawn(config: DnsConfig, dns_handler: DnsHandler) -> Result<Self> {
+    ///
+    /// `tls_material` holds the certificates of the HTTPS server, which are required for
+    /// DNS-over-TLS and DNS-over-QUIC.
+    pub async fn spawn(
+        config: DnsConfig,
+        dns_handler: DnsHandler,
+        tls_material: Option<TlsMaterial>,
+    ) -> Result<Self> {
         const TCP_TIMEOUT: Duration = Duration::from_millis(1000);
         let mut server = hickory_server::ServerFuture::new(dns_handler);
 
@@ -92,9 +151,53 @@ impl DnsServer {
         server.register_listener(TcpListener::bind(bind_addr).await?, TCP_TIMEOUT);
         tracing::info!("DNS server listening on {}", bind_addr);
 
+        let tls_addr = if let Some(tls) = config.tls {
+            let mut tls_config = rustls::ServerConfig::clone(
+                &tls_material
+                    .as_ref()
+                    .context("DNS-over-TLS requires the https config for certificates")?
+                    .config,
+            );
+            // The HTTPS config offers h2 and http/1.1, so the handshake would fail for DoT clients
+            // that offer "dot" (RFC 7858) as protocol.
+            tls_config.alpn_protocols = vec![b"dot".to_vec()];
+            let tls_addr = SocketAddr::new(bind_addr.ip(), tls.port.unwrap_or(DEFAULT_DOT_PORT));
+            let listener = TcpListener::bind(tls_addr).await?;
+            let tls_addr = listener.local_addr()?;
+            server.register_tls_listener_with_tls_config(
+                listener,
+                TCP_TIMEOUT,
+                Arc::new(tls_config),
+            )?;
+            tracing::info!("DNS-over-TLS server listening on {}", tls_addr);
+            Some(tls_addr)
+        } else {
+            None
+        };
+
+        let quic_addr = if let Some(quic) = config.quic {
+            let certificate = tls_material
+                .context("DNS-over-QUIC requires the https config for certificates")?
+                .certificate
+                .context(
+                    "DNS-over-QUIC requires the manual or self_signed cert mode, \
+                     certificates renewed via ACME are not supported",
+                )?;
+            let quic_addr = SocketAddr::new(bind_addr.ip(), quic.port.unwrap_or(DEFAULT_DOQ_PORT));
+            let socket = UdpSocket::bind(quic_addr).await?;
+            let quic_addr = socket.local_addr()?;
+            server.register_quic_listener(socket, TCP_TIMEOUT, certificate, None)?;
+            tracing::info!("DNS-over-QUIC server listening on {}", quic_addr);
+            Some(quic_addr)
+        } else {
+            None
+        };
+
         Ok(Self {
             server,
             local_addr: socket_addr,
+            tls_addr,
+            quic_addr,
         })
     }
 
@@ -103,6 +206,16 @@ impl DnsServer {
         self.local_addr
     }
 
+    /// Get the local address of the DNS-over-TLS listener, if enabled.
+    pub fn tls_addr(&self) -> Option<SocketAddr> {
+        self.tls_addr
+    }
+
+    /// Get the local address of the DNS-over-QUIC socket, if enabled.
+    pub fn quic_addr(&self) -> Option<SocketAddr> {
+        self.quic_addr
+    }
+
     /// Shutdown the server an wait for all tasks to complete.
     pub async fn shutdown(mut self) -> Result<()> {
         self.server.shutdown_gracefully().await?;
@@ -129,14 +242,15 @@ impl DnsHandler {
     /// Create a DNS server given some settings, a connection to the DB for DID-by-username lookups
     /// and the server DID to serve under `_did.<origin>`.
     pub fn new(zone_store: ZoneStore, config: &DnsConfig) -> Result<Self> {
-        let origins = config
-            .origins
-            .iter()
-            .map(Name::from_utf8)
-            .collect::<Result<Vec<_>, _>>()?;
+        let origins = config.origin_names()?;
+        let signers = ZoneSigners::load(&origins, config.dnssec.as_ref())?;
+        for signer in signers.iter() {
+            let ds = signer.ds_record()?;
+            tracing::info!(origin = %signer.origin(), %ds, "DNSSEC enabled");
+        }
 
-        let (static_authority, serial) = create_static_authority(&origins, config)?;
-        let authority = NodeAuthority::new(zone_store, static_authority, origins, serial)?;
+        let static_zone = create_static_zone(&origins, &signers, config)?;
+        let authority = NodeAuthority::new(zone_store, static_zone, origins, signers)?;
         let authority = Arc::new(authority);
 
         let mut catalog = Catalog::new();
@@ -174,6 +288,7 @@ impl RequestHandler for DnsHandler {
         match request.protocol() {
             hickory_server::server::Protocol::Udp => inc!(Metrics, dns_requests_udp),
             hickory_server::server::Protocol::Https => inc!(Metrics, dns_requests_https),
+            hickory_server::server::Protocol::Quic => inc!(Metrics, dns_requests_quic),
             _ => {}
         }
 
@@ -220,10 +335,11 @@ impl ResponseHandler for Handle {
     }
 }
 
-fn create_static_authority(
+fn create_static_zone(
     origins: &[Name],
+    signers: &ZoneSigners,
     config: &DnsConfig,
-) -> Result<(InMemoryAuthority, u32)> {
+) -> Result<StaticZone> {
     let soa = RData::parse(
         RecordType::SOA,
         config.default_soa.split_ascii_whitespace(),
@@ -261,12 +377,21 @@ fn create_static_authority(
                 Record::from_rdata(name.clone(), DEFAULT_NS_TTL, RData::NS(rdata::NS(ns))),
             );
         }
+        if let Some(signer) = signers.get(name) {
+            push_record(&mut records, serial, signer.dnskey_record());
+        }
     }
 
-    let static_authority = InMemoryAuthority::new(Name::root(), records, ZoneType::Primary, false)
+    let keys = records.keys().cloned().collect();
+    let authority = InMemoryAuthority::new(Name::root(), records, ZoneType::Primary, false)
         .map_err(|e| anyhow!(e))?;
 
-    Ok((static_authority, serial))
+    Ok(StaticZone {
+        authority,
+        keys,
+        serial,
+        negative_ttl: soa.minimum(),
+    })
 }
 
 fn push_record(records: &mut BTreeMap<RrKey, RecordSet>, serial: u32, record: Record) {
@@ -275,3 +400,126 @@ fn push_record(records: &mut BTreeMap<RrKey, RecordSet>, serial: u32, record: Re
     record_set.insert(record, serial);
     records.insert(key, record_set);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        path::PathBuf,
+        sync::Arc,
+    };
+
+    use anyhow::Result;
+    use hickory_resolver::{
+        config::{NameServerConfig, Protocol, ResolverConfig},
+        AsyncResolver,
+    };
+    use iroh_net::dns::node_info::IROH_TXT_NAME;
+
+    use super::{DnsConfig, DnsHandler, DnsQuicConfig, DnsServer, DnsTlsConfig};
+    use crate::{
+        config::Config,
+        http::{CertMode, TlsMaterial},
+        store::{PacketSource, ZoneStore},
+        test_utils::{metrics, random_node_packet},
+    };
+
+    /// Create a TLS client config that trusts the self-signed certificate of `tls_material`.
+    fn client_config(tls_material: &TlsMaterial, alpn: &[u8]) -> Result<rustls::ClientConfig> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in tls_material
+            .certificate
+            .as_ref()
+            .expect("self-signed")
+            .0
+            .iter()
+        {
+            roots.add(cert)?;
+        }
+        let mut config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        Ok(config)
+    }
+
+    /// Spawn a DNS server with a self-signed certificate and a stored node packet.
+    ///
+    /// Returns the server, its TLS material and the name of the node's TXT record.
+    async fn spawn_server(
+        f: impl FnOnce(&mut DnsConfig),
+    ) -> Result<(DnsServer, TlsMaterial, String)> {
+        let mut config = Config::default().dns;
+        config.port = 0;
+        config.bind_addr = Some(Ipv4Addr::LOCALHOST.into());
+        f(&mut config);
+        let tls_material = CertMode::SelfSigned
+            .build(vec!["localhost".to_string()], PathBuf::new(), None, false)
+            .await?
+            .material();
+        let store = ZoneStore::in_memory()?;
+        let handler = DnsHandler::new(store.clone(), &config)?;
+        let server = DnsServer::spawn(config, handler, Some(tls_material.clone())).await?;
+
+        let (_secret_key, packet) = random_node_packet()?;
+        let name = format!(
+            "{IROH_TXT_NAME}.{}.irohdns.example.",
+            packet.public_key().to_z32()
+        );
+        store.insert(packet, PacketSource::PkarrPublish).await?;
+        Ok((server, tls_material, name))
+    }
+
+    /// Look up the TXT records at `name` via an encrypted transport.
+    async fn lookup_txt(
+        addr: SocketAddr,
+        protocol: Protocol,
+        tls_material: &TlsMaterial,
+        alpn: &[u8],
+        name: &str,
+    ) -> Result<usize> {
+        let mut nameserver = NameServerConfig::new(addr, protocol);
+        nameserver.tls_dns_name = Some("localhost".to_string());
+        let mut resolver_config = ResolverConfig::new();
+        resolver_config.add_name_server(nameserver);
+        resolver_config.set_tls_client_config(Arc::new(client_config(tls_material, alpn)?));
+        let resolver = AsyncResolver::tokio(resolver_config, Default::default());
+        let lookup = resolver.txt_lookup(name).await?;
+        Ok(lookup.iter().count())
+    }
+
+    #[tokio::test]
+    async fn dns_over_tls() -> Result<()> {
+        let (server, tls_material, name) = spawn_server(|config| {
+            config.tls = Some(DnsTlsConfig { port: Some(0) });
+        })
+        .await?;
+
+        // Clients may require the DoT protocol to be negotiated via ALPN.
+        let addr = server.tls_addr().unwrap();
+        let count = lookup_txt(addr, Protocol::Tls, &tls_material, b"dot", &name).await?;
+        assert_eq!(count, 1);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dns_over_quic() -> Result<()> {
+        let metrics = metrics();
+        let (server, tls_material, name) = spawn_server(|config| {
+            config.quic = Some(DnsQuicConfig { port: Some(0) });
+        })
+        .await?;
+
+        let requests = metrics.dns_requests_quic.get();
+        let addr = server.quic_addr().unwrap();
+        let count = lookup_txt(addr, Protocol::Quic, &tls_material, b"doq", &name).await?;
+        assert_eq!(count, 1);
+        assert!(metrics.dns_requests_quic.get() > requests);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+//! Online DNSSEC signing.
+//!
+//! Each origin with DNSSEC enabled has a single ed25519 key (algorithm 15, see RFC 8080), which
+//! is used as combined key and zone signing key. Record sets are signed when they are served,
+//! because pkarr zones change at any time. Signatures are cached and refreshed once half of
+//! their validity has passed.
+//!
+//! Denial of existence uses NSEC "white lies" (RFC 4470): the NSEC records are synthesized to
+//! cover only the queried name, so that they can't be used to enumerate the stored pubkeys.
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use hickory_proto::rr::{
+    dnssec::{
+        rdata::{DNSSECRData, DNSKEY, DS, NSEC, RRSIG},
+        tbs, Algorithm, DigestType,
+    },
+    DNSClass, Name, RData, Record, RecordSet, RecordType,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::config::Config;
+
+/// Signatures are valid for 7 days by default
+pub const DEFAULT_SIGNATURE_VALIDITY: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// Start the validity of signatures 1 hour in the past, to allow for clock skew of validators.
+const SIGNATURE_INCEPTION_OFFSET: u64 = 60 * 60;
+
+/// TTL of the DNSKEY and DS records
+const DNSKEY_TTL: u32 = 60 * 60; // 1h
+
+/// DNSSEC settings
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DnssecConfig {
+    /// Paths to the signing key files, by origin.
+    ///
+    /// Each file contains a hex encoded ed25519 secret key. If a file does not exist, a new key
+    /// is generated and written to it. Relative paths are resolved against
+    /// [`Config::data_dir`].
+    pub keys: BTreeMap<String, PathBuf>,
+    /// Validity of signatures in seconds. Defaults to 7 days.
+    pub signature_validity_secs: Option<u64>,
+}
+
+/// Signs the record sets of an origin.
+#[derive(derive_more::Debug)]
+pub struct ZoneSigner {
+    origin: Name,
+    #[debug("Keypair")]
+    keypair: pkarr::Keypair,
+    dnskey: DNSKEY,
+    key_tag: u16,
+    validity: Duration,
+}
+
+impl ZoneSigner {
+    /// Create a signer for `origin` with an ed25519 keypair.
+    pub fn new(origin: Name, keypair: pkarr::Keypair, validity: Duration) -> Result<Self> {
+        let public_key = keypair.public_key().to_bytes().to_vec();
+        // Zone key with the secure entry point flag, as it is also the key signing key.
+        let dnskey = DNSKEY::new(true, true, false, Algorithm::ED25519, public_key);
+        let key_tag = dnskey.calculate_key_tag()?;
+        Ok(Self {
+            origin,
+            keypair,
+            dnskey,
+            key_tag,
+            validity,
+        })
+    }
+
+    /// Create a signer for `origin` with the key stored at `path`.
+    ///
+    /// If the file does not exist, a new key is generated and written to it, readable only by
+    /// the current user. On unix, existing key files that other users can access are refused.
+    pub fn load(origin: Name, path: &Path, validity: Duration) -> Result<Self> {
+        let keypair = if path.exists() {
+            check_key_file_permissions(path)?;
+            let key = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read DNSSEC key from {}", path.display()))?;
+            let key: [u8; 32] = hex::decode(key.trim())?
+                .try_into()
+                .map_err(|_| anyhow!("invalid DNSSEC key length in {}", path.display()))?;
+            pkarr::Keypair::from_secret_key(&key)
+        } else {
+            let keypair = pkarr::Keypair::random();
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            write_key_file(path, &hex::encode(keypair.secret_key()))
+                .with_context(|| format!("failed to write DNSSEC key to {}", path.display()))?;
+            info!(%origin, path = %path.display(), "generated new DNSSEC key");
+            keypair
+        };
+        Self::new(origin, keypair, validity)
+    }
+
+    /// The origin of the zone.
+    pub fn origin(&self) -> &Name {
+        &self.origin
+    }
+
+    /// The DNSKEY record to serve at the origin.
+    pub fn dnskey_record(&self) -> Record {
+        let rdata = RData::DNSSEC(DNSSECRData::DNSKEY(self.dnskey.clone()));
+        Record::from_rdata(self.origin.clone(), DNSKEY_TTL, rdata)
+    }
+
+    /// The DS record to publish in the parent zone.
+    pub fn ds_record(&self) -> Result<Record> {
+        let digest = self.dnskey.to_digest(&self.origin, DigestType::SHA256)?;
+        let ds = DS::new(
+            self.key_tag,
+            Algorithm::ED25519,
+            DigestType::SHA256,
+            digest.as_ref().to_vec(),
+        );
+        let rdata = RData::DNSSEC(DNSSECRData::DS(ds));
+        Ok(Record::from_rdata(self.origin.clone(), DNSKEY_TTL, rdata))
+    }
+
+    /// Sign a record set and add the RRSIG record to it.
+    ///
+    /// Returns the time at which the signature should be refreshed, in seconds since the unix
+    /// epoch.
+    pub fn sign(&self, record_set: &mut RecordSet) -> Result<u64> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        let inception = now.saturating_sub(SIGNATURE_INCEPTION_OFFSET) as u32;
+        let expiration = (now + self.validity.as_secs()) as u32;
+        let name = record_set.name().clone();
+        let record_type = record_set.record_type();
+        let ttl = record_set.ttl();
+        let records: Vec<Record> = record_set.records_without_rrsigs().cloned().collect();
+        let tbs = tbs::rrset_tbs(
+            &name,
+            DNSClass::IN,
+            name.num_labels(),
+            record_type,
+            Algorithm::ED25519,
+            ttl,
+            expiration,
+            inception,
+            self.key_tag,
+            &self.origin,
+            &records,
+        )?;
+        let signature = self.keypair.sign(tbs.as_ref());
+        let rrsig = RRSIG::new(
+            record_type,
+            Algorithm::ED25519,
+            name.num_labels(),
+            ttl,
+            expiration,
+            inception,
+            self.key_tag,
+            self.origin.clone(),
+            signature.to_bytes().to_vec(),
+        );
+        let rdata = RData::DNSSEC(DNSSECRData::RRSIG(rrsig));
+        record_set.insert_rrsig(Record::from_rdata(name, ttl, rdata));
+        Ok(now + self.validity.as_secs() / 2)
+    }
+
+    /// Synthesize a signed NSEC record proving that `name` has no records except of `types`.
+    pub fn nsec_nodata(&self, name: &Name, types: &[RecordType], ttl: u32) -> Result<RecordSet> {
+        let mut types = types.to_vec();
+        types.extend([RecordType::RRSIG, RecordType::NSEC]);
+        types.sort();
+        types.dedup();
+        self.signed_nsec(name.clone(), successor(name)?, types, ttl)
+    }
+
+    /// Synthesize signed NSEC records proving that `name` does not exist.
+    ///
+    /// The records cover only `name` and the wildcard name at its parent.
+    pub fn nsec_nxdomain(&self, name: &Name, ttl: u32) -> Result<Vec<RecordSet>> {
+        let types = vec![RecordType::RRSIG, RecordType::NSEC];
+        let mut record_sets =
+            vec![self.signed_nsec(predecessor(name)?, successor(name)?, types.clone(), ttl)?];
+        let wildcard = prepend_label(b"*", &name.base_name())?;
+        if wildcard != *name {
+            record_sets.push(self.signed_nsec(
+                predecessor(&wildcard)?,
+                successor(&wildcard)?,
+                types,
+                ttl,
+            )?);
+        }
+        Ok(record_sets)
+    }
+
+    fn signed_nsec(
+        &self,
+        owner: Name,
+        next: Name,
+        types: Vec<RecordType>,
+        ttl: u32,
+    ) -> Result<RecordSet> {
+        let rdata = RData::DNSSEC(DNSSECRData::NSEC(NSEC::new(next, types)));
+        let mut record_set = RecordSet::from(Record::from_rdata(owner, ttl, rdata));
+        self.sign(&mut record_set)?;
+        Ok(record_set)
+    }
+}
+
+/// Create a new key file that only the current user can read and write.
+fn write_key_file(path: &Path, key: &str) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(key.as_bytes())?;
+    Ok(())
+}
+
+/// Refuse key files that users other than the owner can access.
+#[cfg(unix)]
+fn check_key_file_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    ensure!(
+        mode & 0o077 == 0,
+        "DNSSEC key file {} is accessible by other users (mode {:o}), restrict it with `chmod 600`",
+        path.display(),
+        mode & 0o777
+    );
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_key_file_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// The name immediately following `name` in canonical order, see RFC 4034, section 6.1.
+fn successor(name: &Name) -> Result<Name> {
+    prepend_label(&[0u8], name)
+}
+
+/// A name shortly before `name` in canonical order, see RFC 4034, section 6.1.
+///
+/// No existing name sorts between the returned name and `name`, unless its first label ends
+/// with a run of `\255` octets.
+fn predecessor(name: &Name) -> Result<Name> {
+    let parent = name.base_name();
+    let mut label = name
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("the root name has no predecessor"))?
+        .to_ascii_lowercase();
+    let last = label.pop().expect("labels are not empty");
+    if last == 0 {
+        // The label without its last octet sorts before it.
+        if label.is_empty() {
+            return Ok(parent);
+        }
+        return prepend_label(&label, &parent);
+    }
+    // Decrement the last octet, and append octets up to the maximum label length.
+    let mut last = last - 1;
+    if last.is_ascii_uppercase() {
+        // Names are compared case-insensitively, `@` sorts before `[`.
+        last = b'@';
+    }
+    label.push(last);
+    let unpadded = prepend_label(&label, &parent)?;
+    label.resize(63, 0xff);
+    // Fall back to the unpadded label if the name gets too long.
+    Ok(prepend_label(&label, &parent).unwrap_or(unpadded))
+}
+
+/// Prepend a label in raw bytes to `name`.
+fn prepend_label(label: &[u8], name: &Name) -> Result<Name> {
+    Ok(Name::from_labels([label])?.append_name(name)?)
+}
+
+/// The signers of all origins with DNSSEC enabled.
+#[derive(Debug, Default)]
+pub struct ZoneSigners {
+    signers: BTreeMap<Name, ZoneSigner>,
+}
+
+impl ZoneSigners {
+    /// Load the signing keys configured for `origins`.
+    pub fn load(origins: &[Name], config: Option<&DnssecConfig>) -> Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self::default());
+        };
+        let validity = config
+            .signature_validity_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_SIGNATURE_VALIDITY);
+        let mut signers = BTreeMap::new();
+        for (origin, path) in config.keys.iter() {
+            let origin = Name::from_utf8(origin)?;
+            ensure!(
+                origins.contains(&origin),
+                "DNSSEC key configured for unknown origin {origin}"
+            );
+            let path = Config::data_dir()?.join(path);
+            let signer = ZoneSigner::load(origin.clone(), &path, validity)?;
+            signers.insert(origin, signer);
+        }
+        Ok(Self { signers })
+    }
+
+    /// Get the signer for an origin.
+    pub fn get(&self, origin: &Name) -> Option<&ZoneSigner> {
+        self.signers.get(origin)
+    }
+
+    /// Iterate over all signers.
+    pub fn iter(&self) -> impl Iterator<Item = &ZoneSigner> {
+        self.signers.values()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::test_utils::TempPath;
+
+    #[test]
+    fn key_file_permissions() -> Result<()> {
+        let origin = Name::from_utf8("irohdns.example.")?;
+        let path = TempPath::new("key");
+        let signer = ZoneSigner::load(origin.clone(), &path, DEFAULT_SIGNATURE_VALIDITY)?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // The generated key is loaded again.
+        let loaded = ZoneSigner::load(origin.clone(), &path, DEFAULT_SIGNATURE_VALIDITY)?;
+        assert_eq!(loaded.key_tag, signer.key_tag);
+
+        // Unless others can read it.
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+        assert!(ZoneSigner::load(origin, &path, DEFAULT_SIGNATURE_VALIDITY).is_err());
+        Ok(())
+    }
+}
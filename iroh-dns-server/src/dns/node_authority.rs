@@ -1,34 +1,58 @@
-use std::{fmt, sync::Arc};
+use std::{collections::HashMap, fmt, sync::Arc, time::SystemTime};
 
 use anyhow::{bail, ensure, Result};
 use async_trait::async_trait;
 use hickory_proto::{
-    op::ResponseCode,
-    rr::{LowerName, Name, RecordType},
+    op::{Message, MessageType, ResponseCode},
+    rr::{
+        dnssec::{
+            rdata::{DNSSECRData, KEY},
+            Algorithm, Verifier,
+        },
+        DNSClass, LowerName, Name, RData, Record, RecordSet, RecordType, RrKey,
+    },
 };
 use hickory_server::{
     authority::{
         AuthLookup, Authority, LookupError, LookupOptions, LookupRecords, MessageRequest,
-        UpdateResult, ZoneType,
+        UpdateRequest, UpdateResult, ZoneType,
     },
     server::RequestInfo,
     store::in_memory::InMemoryAuthority,
 };
+use parking_lot::Mutex;
 
 use tracing::{debug, trace};
 
+use super::{ZoneSigner, ZoneSigners};
 use crate::{
-    store::ZoneStore,
-    util::{record_set_append_origin, PublicKeyBytes},
+    store::{PacketSource, UpsertOutcome, ZoneStore, ZoneUpdate, MAX_ZONE_UPDATE_SIZE},
+    util::{record_set_append_origin, signed_packet_to_hickory_message, PublicKeyBytes},
 };
 
+/// Accept SIG(0) signatures whose inception is up to 5 minutes in the future.
+const MAX_SIG0_CLOCK_SKEW: u32 = 5 * 60;
+
+/// The static records served for all origins.
+#[derive(derive_more::Debug)]
+pub struct StaticZone {
+    #[debug("InMemoryAuthority")]
+    pub authority: InMemoryAuthority,
+    /// The names and types of all static record sets.
+    pub keys: Vec<RrKey>,
+    pub serial: u32,
+    /// TTL for the denial of existence, i.e. the minimum field of the SOA record.
+    pub negative_ttl: u32,
+}
+
 #[derive(derive_more::Debug)]
 pub struct NodeAuthority {
-    serial: u32,
     origins: Vec<Name>,
-    #[debug("InMemoryAuthority")]
-    static_authority: InMemoryAuthority,
+    static_zone: StaticZone,
     zones: ZoneStore,
+    signers: ZoneSigners,
+    /// Signed static record sets, with the time at which to refresh them.
+    static_signatures: Mutex<HashMap<RrKey, (Arc<RecordSet>, u64)>>,
     // TODO: This is used by Authority::origin
     // Find out what exactly this is used for - we don't have a primary origin.
     first_origin: LowerName,
@@ -37,17 +61,18 @@ pub struct NodeAuthority {
 impl NodeAuthority {
     pub fn new(
         zones: ZoneStore,
-        static_authority: InMemoryAuthority,
+        static_zone: StaticZone,
         origins: Vec<Name>,
-        serial: u32,
+        signers: ZoneSigners,
     ) -> Result<Self> {
         ensure!(!origins.is_empty(), "at least one origin is required");
         let first_origin = LowerName::from(&origins[0]);
         Ok(Self {
-            static_authority,
+            static_zone,
             origins,
-            serial,
             zones,
+            signers,
+            static_signatures: Default::default(),
             first_origin,
         })
     }
@@ -57,7 +82,138 @@ impl NodeAuthority {
     }
 
     pub fn serial(&self) -> u32 {
-        self.serial
+        self.static_zone.serial
+    }
+
+    /// Resolve a record set of a pkarr zone, with its full name below `origin`.
+    ///
+    /// The record set is signed if requested and DNSSEC is enabled for the origin.
+    async fn resolve_pkarr(
+        &self,
+        pubkey: &PublicKeyBytes,
+        name: &Name,
+        record_type: RecordType,
+        origin: &Name,
+        lookup_options: LookupOptions,
+    ) -> Result<Option<Arc<RecordSet>>> {
+        if let Some(signer) = self.signers.get(origin) {
+            if lookup_options.is_dnssec() {
+                return self
+                    .zones
+                    .resolve_signed(pubkey, name, record_type, signer, self.serial())
+                    .await;
+            }
+        }
+        let Some(pkarr_set) = self.zones.resolve(pubkey, name, record_type).await? else {
+            return Ok(None);
+        };
+        let new_origin = Name::parse(&pubkey.to_z32(), Some(origin))?;
+        let record_set = record_set_append_origin(&pkarr_set, &new_origin, self.serial())?;
+        Ok(Some(Arc::new(record_set)))
+    }
+
+    /// Get the types of the records at `name`, or `None` if the name does not exist.
+    ///
+    /// A name without records exists if names below it have records.
+    async fn record_types(&self, name: &Name) -> Result<Option<Vec<RecordType>>> {
+        if let Ok((name, pubkey, _origin)) = split_and_parse_pkarr(name.clone(), &self.origins) {
+            return self.zones.record_types(&pubkey, &name).await;
+        }
+        let name = LowerName::from(name);
+        let mut exists = false;
+        let mut types = vec![];
+        for key in self.static_zone.keys.iter() {
+            if key.name == name {
+                types.push(key.record_type);
+                exists = true;
+            } else if name.zone_of(&key.name) {
+                exists = true;
+            }
+        }
+        Ok(exists.then_some(types))
+    }
+
+    /// Get the signer of the closest origin that contains `name`, if DNSSEC is enabled for it.
+    fn signer_for(&self, name: &Name) -> Option<&ZoneSigner> {
+        let origin = self
+            .origins
+            .iter()
+            .filter(|origin| origin.zone_of(name))
+            .max_by_key(|origin| origin.num_labels())?;
+        self.signers.get(origin)
+    }
+
+    /// Look up static records, signing them if requested and DNSSEC is enabled.
+    async fn lookup_static(
+        &self,
+        name: &LowerName,
+        record_type: RecordType,
+        lookup_options: LookupOptions,
+    ) -> Result<AuthLookup, LookupError> {
+        let lookup = self
+            .static_zone
+            .authority
+            .lookup(name, record_type, lookup_options)
+            .await?;
+        if !lookup_options.is_dnssec() {
+            return Ok(lookup);
+        }
+        let lookup = match lookup {
+            AuthLookup::Records {
+                answers,
+                additionals,
+            } => AuthLookup::Records {
+                answers: self.sign_static(answers)?,
+                additionals: additionals.map(|r| self.sign_static(r)).transpose()?,
+            },
+            AuthLookup::SOA(records) => AuthLookup::SOA(self.sign_static(records)?),
+            lookup => lookup,
+        };
+        Ok(lookup)
+    }
+
+    fn sign_static(&self, records: LookupRecords) -> Result<LookupRecords, LookupError> {
+        let records = match records {
+            LookupRecords::Records {
+                lookup_options,
+                records,
+            } => LookupRecords::Records {
+                lookup_options,
+                records: self.sign_static_record_set(records)?,
+            },
+            LookupRecords::ManyRecords(lookup_options, record_sets) => {
+                let record_sets = record_sets
+                    .into_iter()
+                    .map(|rset| self.sign_static_record_set(rset))
+                    .collect::<Result<_, _>>()?;
+                LookupRecords::ManyRecords(lookup_options, record_sets)
+            }
+            records => records,
+        };
+        Ok(records)
+    }
+
+    fn sign_static_record_set(&self, rset: Arc<RecordSet>) -> Result<Arc<RecordSet>, LookupError> {
+        let Some(signer) = self.signer_for(rset.name()) else {
+            return Ok(rset);
+        };
+        let key = RrKey::new(rset.name().into(), rset.record_type());
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(err_refused)?
+            .as_secs();
+        if let Some((signed, refresh_at)) = self.static_signatures.lock().get(&key) {
+            if *refresh_at > now {
+                return Ok(signed.clone());
+            }
+        }
+        let mut signed = RecordSet::clone(&rset);
+        let refresh_at = signer.sign(&mut signed).map_err(err_refused)?;
+        let signed = Arc::new(signed);
+        self.static_signatures
+            .lock()
+            .insert(key, (signed.clone(), refresh_at));
+        Ok(signed)
     }
 }
 
@@ -73,8 +229,105 @@ impl Authority for NodeAuthority {
         false
     }
 
-    async fn update(&self, _update: &MessageRequest) -> UpdateResult<bool> {
-        Err(ResponseCode::NotImp)
+    /// Apply a dynamic update (RFC 2136) to a pkarr zone.
+    ///
+    /// The zone of the update must be `<z32 pubkey>.<origin>`, and the update must be signed with
+    /// SIG(0) by the ed25519 key of the pubkey. Prerequisites are not supported.
+    ///
+    /// The server can't create a pkarr signature for the updated zone, so it is stored as a
+    /// [`ZoneUpdate`] on top of the signed packet, and served over DNS only until a newer signed
+    /// packet is published for the pubkey.
+    async fn update(&self, update: &MessageRequest) -> UpdateResult<bool> {
+        let zone = Name::from(update.zone().name());
+        let (name, pubkey, origin) =
+            split_and_parse_pkarr(zone.clone(), &self.origins).map_err(|err| {
+                debug!(%zone, ?err, "update: not a pkarr zone");
+                ResponseCode::NotZone
+            })?;
+        if !name.is_root() {
+            debug!(%zone, "update: not the apex of a pkarr zone");
+            return Err(ResponseCode::NotZone);
+        }
+        verify_sig0(update, &pubkey)?;
+        if !update.prerequisites().is_empty() {
+            return Err(ResponseCode::NotImp);
+        }
+
+        let packet = self
+            .zones
+            .resolve_signed_packet(&pubkey)
+            .await
+            .map_err(err_serv_fail)?;
+        let previous = self
+            .zones
+            .get_zone_update(&pubkey)
+            .await
+            .map_err(err_serv_fail)?
+            .filter(|previous| previous.applies_to(packet.as_ref()));
+        let mut records = match (&previous, &packet) {
+            (Some(previous), _) => Message::from_vec(&previous.encoded_packet)
+                .map_err(err_serv_fail)?
+                .take_answers(),
+            (None, Some(packet)) => signed_packet_to_hickory_message(packet)
+                .map_err(err_serv_fail)?
+                .take_answers(),
+            (None, None) => vec![],
+        };
+        let zone_class = update.zone().query_class();
+        for record in update.updates() {
+            if !zone.zone_of(record.name()) {
+                debug!(%zone, name = %record.name(), "update: record outside of the zone");
+                return Err(ResponseCode::NotZone);
+            }
+            // Names in pkarr packets end with the pubkey, without the origin.
+            let labels = record.name().num_labels() - origin.num_labels();
+            let name = Name::from_labels(record.name().iter().take(labels as usize))
+                .map_err(err_serv_fail)?;
+            let mut record = record.clone();
+            record.set_name(name);
+            apply_update(&mut records, record, zone_class)?;
+        }
+
+        let mut message = Message::new();
+        message.set_message_type(MessageType::Response);
+        message.add_answers(records);
+        let encoded = message.to_vec().map_err(err_serv_fail)?;
+        if encoded.len() > MAX_ZONE_UPDATE_SIZE {
+            debug!(%zone, len = encoded.len(), "update: zone too large");
+            return Err(ResponseCode::Refused);
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(err_serv_fail)?
+            .as_micros() as u64;
+        // The timestamp only orders the updates on top of the same signed packet.
+        let timestamp = match &previous {
+            Some(previous) => now.max(previous.timestamp + 1),
+            None => now,
+        };
+        let zone_update = ZoneUpdate {
+            pubkey,
+            base_timestamp: packet.map_or(0, |packet| *packet.timestamp()),
+            timestamp,
+            encoded_packet: encoded.into(),
+        };
+        let outcome = self
+            .zones
+            .insert_zone_update(
+                zone_update,
+                previous.map(|previous| previous.timestamp),
+                PacketSource::DnsUpdate,
+            )
+            .await
+            .map_err(err_serv_fail)?;
+        debug!(%zone, ?outcome, "update: applied");
+        match outcome {
+            UpsertOutcome::Stored | UpsertOutcome::Unchanged => Ok(true),
+            // The zone was changed while applying the update, the client may retry.
+            UpsertOutcome::Outdated | UpsertOutcome::PreconditionFailed => {
+                Err(ResponseCode::ServFail)
+            }
+        }
     }
 
     fn origin(&self) -> &LowerName {
@@ -89,37 +342,38 @@ impl Authority for NodeAuthority {
     ) -> Result<Self::Lookup, LookupError> {
         match record_type {
             RecordType::SOA | RecordType::NS => {
-                self.static_authority
-                    .lookup(name, record_type, lookup_options)
-                    .await
+                self.lookup_static(name, record_type, lookup_options).await
             }
             _ => match split_and_parse_pkarr(name, &self.origins) {
                 Err(err) => {
                     trace!(%name, ?err, "name is not a pkarr zone");
                     debug!("resolve static: name {name}");
-                    self.static_authority
-                        .lookup(name, record_type, lookup_options)
-                        .await
+                    self.lookup_static(name, record_type, lookup_options).await
                 }
                 Ok((name, pubkey, origin)) => {
                     debug!(%origin, "resolve pkarr: {name} {pubkey}");
-                    match self
-                        .zones
-                        .resolve(&pubkey, &name, record_type)
+                    let record_set = self
+                        .resolve_pkarr(&pubkey, &name, record_type, &origin, lookup_options)
                         .await
-                        .map_err(err_refused)?
-                    {
-                        Some(pkarr_set) => {
-                            let new_origin = Name::parse(&pubkey.to_z32(), Some(&origin))
-                                .map_err(err_refused)?;
-                            let record_set =
-                                record_set_append_origin(&pkarr_set, &new_origin, self.serial())
-                                    .map_err(err_refused)?;
-                            let records = LookupRecords::new(lookup_options, Arc::new(record_set));
+                        .map_err(err_refused)?;
+                    match record_set {
+                        Some(record_set) => {
+                            let records = LookupRecords::new(lookup_options, record_set);
                             let answers = AuthLookup::answers(records, None);
                             Ok(answers)
                         }
-                        None => Err(err_nx_domain("not found")),
+                        None => {
+                            let types = self
+                                .zones
+                                .record_types(&pubkey, &name)
+                                .await
+                                .map_err(err_refused)?;
+                            match types {
+                                // The name has other records: answer with NODATA.
+                                Some(_) => Err(LookupError::NameExists),
+                                None => Err(err_nx_domain("not found")),
+                            }
+                        }
                     }
                 }
             },
@@ -136,8 +390,7 @@ impl Authority for NodeAuthority {
         let record_type: RecordType = request_info.query.query_type();
         match record_type {
             RecordType::SOA => {
-                self.static_authority
-                    .lookup(self.origin(), record_type, lookup_options)
+                self.lookup_static(self.origin(), record_type, lookup_options)
                     .await
             }
             RecordType::AXFR => Err(LookupError::from(ResponseCode::Refused)),
@@ -147,10 +400,22 @@ impl Authority for NodeAuthority {
 
     async fn get_nsec_records(
         &self,
-        _name: &LowerName,
-        _lookup_options: LookupOptions,
+        name: &LowerName,
+        lookup_options: LookupOptions,
     ) -> Result<Self::Lookup, LookupError> {
-        Ok(AuthLookup::default())
+        let name = Name::from(name);
+        let Some(signer) = self.signer_for(&name) else {
+            return Ok(AuthLookup::default());
+        };
+        let ttl = self.static_zone.negative_ttl;
+        let record_sets = match self.record_types(&name).await.map_err(err_refused)? {
+            Some(types) => signer.nsec_nodata(&name, &types, ttl).map(|set| vec![set]),
+            None => signer.nsec_nxdomain(&name, ttl),
+        }
+        .map_err(err_refused)?;
+        let record_sets = record_sets.into_iter().map(Arc::new).collect();
+        let records = LookupRecords::ManyRecords(lookup_options, record_sets);
+        Ok(AuthLookup::answers(records, None))
     }
 }
 
@@ -180,6 +445,99 @@ fn split_and_parse_pkarr(
     bail!("name does not match any origin");
 }
 
+/// Check that an update is signed with SIG(0) by the ed25519 key of `pubkey`.
+fn verify_sig0(update: &MessageRequest, pubkey: &PublicKeyBytes) -> UpdateResult<()> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(err_serv_fail)?
+        .as_secs() as u32;
+    let key = KEY::new(
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Algorithm::ED25519,
+        pubkey.to_bytes().to_vec(),
+    );
+    let verified = update
+        .sig0()
+        .iter()
+        .filter_map(|sig0| {
+            sig0.data()
+                .and_then(RData::as_dnssec)
+                .and_then(DNSSECRData::as_sig)
+        })
+        .filter(|sig| {
+            sig.algorithm() == Algorithm::ED25519
+                && sig.sig_inception() <= now.saturating_add(MAX_SIG0_CLOCK_SKEW)
+                && now <= sig.sig_expiration()
+        })
+        .any(|sig| key.verify_message(update, sig.sig(), sig).is_ok());
+    if !verified {
+        debug!(%pubkey, "update: no valid SIG(0) by the zone key");
+        return Err(ResponseCode::Refused);
+    }
+    Ok(())
+}
+
+/// Apply a single update record to the records of a zone, see RFC 2136, section 3.4.2.
+fn apply_update(
+    records: &mut Vec<Record>,
+    update: Record,
+    zone_class: DNSClass,
+) -> UpdateResult<()> {
+    let name = LowerName::from(update.name());
+    let record_type = update.record_type();
+    if matches!(record_type, RecordType::SOA | RecordType::NS) {
+        // The apex records are served from the static zone.
+        return Err(ResponseCode::Refused);
+    }
+    let class = update.dns_class();
+    if class == zone_class {
+        // Add a record, replacing an equal one.
+        if update.data().is_none()
+            || matches!(
+                record_type,
+                RecordType::ANY | RecordType::AXFR | RecordType::IXFR
+            )
+        {
+            return Err(ResponseCode::FormErr);
+        }
+        records.retain(|record| {
+            !(LowerName::from(record.name()) == name
+                && record.record_type() == record_type
+                && record.data() == update.data())
+        });
+        records.push(update);
+    } else if class == DNSClass::ANY {
+        // Delete a record set, or all records at a name.
+        if update.ttl() != 0 || update.data().is_some() {
+            return Err(ResponseCode::FormErr);
+        }
+        records.retain(|record| {
+            !(LowerName::from(record.name()) == name
+                && (record_type == RecordType::ANY || record.record_type() == record_type))
+        });
+    } else if class == DNSClass::NONE {
+        // Delete a record.
+        if update.ttl() != 0 || update.data().is_none() {
+            return Err(ResponseCode::FormErr);
+        }
+        records.retain(|record| {
+            !(LowerName::from(record.name()) == name
+                && record.record_type() == record_type
+                && record.data() == update.data())
+        });
+    } else {
+        return Err(ResponseCode::FormErr);
+    }
+    Ok(())
+}
+
+fn err_serv_fail(e: impl fmt::Debug) -> ResponseCode {
+    debug!("update failed (servfail): {e:?}");
+    ResponseCode::ServFail
+}
 fn err_refused(e: impl fmt::Debug) -> LookupError {
     trace!("lookup failed (refused): {e:?}");
     LookupError::from(ResponseCode::Refused)
@@ -28,12 +28,16 @@ mod doh;
 mod error;
 mod pkarr;
 mod rate_limiting;
+mod replication;
 mod tls;
 
 use crate::state::AppState;
 use crate::{config::Config, metrics::Metrics};
 
-pub use self::tls::CertMode;
+pub use self::tls::{CertMode, CertificateAndKey, TlsMaterial};
+
+/// Allow up to 10000 concurrently open watch streams by default
+pub const DEFAULT_MAX_WATCHERS: usize = 10_000;
 
 /// Config for the HTTP server
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -66,6 +70,7 @@ pub struct HttpServer {
     tasks: JoinSet<std::io::Result<()>>,
     http_addr: Option<SocketAddr>,
     https_addr: Option<SocketAddr>,
+    tls_material: Option<TlsMaterial>,
 }
 
 impl HttpServer {
@@ -102,6 +107,7 @@ impl HttpServer {
         };
 
         // launch https
+        let mut tls_material = None;
         let https_addr = if let Some(config) = https_config {
             let bind_addr = SocketAddr::new(
                 config.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
@@ -126,6 +132,7 @@ impl HttpServer {
                     )
                     .await?
             };
+            tls_material = Some(acceptor.material());
             let listener = TcpListener::bind(bind_addr).await?.into_std()?;
             let bound_addr = listener.local_addr()?;
             let fut = axum_server::from_tcp(listener)
@@ -142,6 +149,7 @@ impl HttpServer {
             tasks,
             http_addr,
             https_addr,
+            tls_material,
         })
     }
 
@@ -155,6 +163,11 @@ impl HttpServer {
         self.https_addr
     }
 
+    /// Get the TLS material of the HTTPS server, to serve other protocols with its certificates.
+    pub fn tls_material(&self) -> Option<TlsMaterial> {
+        self.tls_material.clone()
+    }
+
     /// Shutdown the server and wait for all tasks to complete.
     pub async fn shutdown(mut self) -> Result<()> {
         // TODO: Graceful cancellation.
@@ -212,16 +225,23 @@ pub(crate) fn create_app(state: AppState) -> Router {
 
     // configure rate limiting middleware
     let rate_limit = rate_limiting::create();
+    let watch_rate_limit = rate_limiting::create();
 
     // configure routes
     //
-    // only the pkarr::put route gets a rate limit
+    // only the pkarr::put and pkarr::watch routes get a rate limit, with separate budgets
     let router = Router::new()
         .route("/dns-query", get(doh::get).post(doh::post))
         .route(
             "/pkarr/:key",
             get(pkarr::get).put(pkarr::put.layer(rate_limit)),
         )
+        .route("/pkarr/:key/history", get(pkarr::history))
+        .route(
+            "/pkarr/:key/watch",
+            get(pkarr::watch.layer(watch_rate_limit)),
+        )
+        .route("/replication/packets", get(replication::packets))
         .route("/healthcheck", get(|| async { "OK" }))
         .route("/", get(|| async { "Hi!" }))
         .with_state(state);
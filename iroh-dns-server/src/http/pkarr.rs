@@ -1,52 +1,257 @@
+use std::{
+    convert::Infallible,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use anyhow::Result;
 use axum::extract::Path;
-use axum::{extract::State, response::IntoResponse};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
 use bytes::Bytes;
 
-use http::{header, StatusCode};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use pkarr::SignedPacket;
+use serde::Serialize;
 
-use tracing::info;
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::{info, warn};
 
-use crate::util::PublicKeyBytes;
-use crate::{state::AppState, store::PacketSource};
+use crate::util::{signed_packet_to_hickory_message, PublicKeyBytes};
+use crate::{
+    state::AppState,
+    store::{PacketSource, PacketWatcher, UpsertOutcome, ZoneStore},
+};
 
 use super::error::AppError;
 
 pub async fn put(
     State(state): State<AppState>,
     Path(key): Path<String>,
+    headers: HeaderMap,
     body: Bytes,
 ) -> Result<impl IntoResponse, AppError> {
     let key = pkarr::PublicKey::try_from(key.as_str())
         .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))?;
     let label = &key.to_z32()[..10];
-    let signed_packet = pkarr::SignedPacket::from_relay_response(key, body).map_err(|e| {
-        AppError::new(
-            StatusCode::BAD_REQUEST,
-            Some(format!("invalid body payload: {e}")),
-        )
-    })?;
-
-    let updated = state
+    let pubkey = PublicKeyBytes::from(key.to_bytes());
+    let signed_packet =
+        pkarr::SignedPacket::from_relay_response(key, body.clone()).map_err(|e| {
+            AppError::new(
+                StatusCode::BAD_REQUEST,
+                Some(format!("invalid body payload: {e}")),
+            )
+        })?;
+    // Invalid dates are ignored, see RFC 9110, section 13.1.4.
+    let unmodified_since = headers
+        .get(header::IF_UNMODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+        .and_then(|since| since.duration_since(UNIX_EPOCH).ok())
+        // HTTP dates have a precision of seconds, see the `Last-Modified` header in [`get`].
+        .map(|since| since.as_secs() * 1_000_000 + 999_999);
+
+    let outcome = state
         .store
-        .insert(signed_packet, PacketSource::PkarrPublish)
+        .insert_conditional(signed_packet, PacketSource::PkarrPublish, unmodified_since)
         .await?;
-    info!(key = %label, ?updated, "pkarr upsert");
-    Ok(StatusCode::NO_CONTENT)
+    info!(key = %label, ?outcome, "pkarr upsert");
+    match outcome {
+        UpsertOutcome::Stored => {
+            if let Some(forwarder) = state.forwarder.as_ref() {
+                forwarder.forward(&pubkey, body);
+            }
+            Ok(StatusCode::NO_CONTENT)
+        }
+        UpsertOutcome::Unchanged => Ok(StatusCode::NO_CONTENT),
+        UpsertOutcome::Outdated => Err(AppError::new(
+            StatusCode::CONFLICT,
+            Some("a more recent packet is already stored".to_string()),
+        )),
+        UpsertOutcome::PreconditionFailed => {
+            Err(AppError::with_status(StatusCode::PRECONDITION_FAILED))
+        }
+    }
 }
 
 pub async fn get(
     State(state): State<AppState>,
     Path(pubkey): Path<String>,
-) -> Result<impl IntoResponse, AppError> {
+    request_headers: HeaderMap,
+) -> Result<Response, AppError> {
     let pubkey = PublicKeyBytes::from_z32(&pubkey)
         .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))?;
     let signed_packet = state
         .store
-        .get_signed_packet(&pubkey)
+        .resolve_signed_packet(&pubkey)
         .await?
         .ok_or_else(|| AppError::with_status(StatusCode::NOT_FOUND))?;
-    let body = signed_packet.as_relay_request();
-    let headers = [(header::CONTENT_TYPE, "application/x-pkarr-signed-packet")];
-    Ok((headers, body))
+
+    // The pkarr timestamp identifies the packet for a pubkey.
+    let timestamp = *signed_packet.timestamp();
+    let etag = format!("\"{timestamp}\"");
+    let last_modified = UNIX_EPOCH + Duration::from_secs(timestamp / 1_000_000);
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, header_value(&etag)?);
+    headers.insert(
+        header::LAST_MODIFIED,
+        header_value(&httpdate::fmt_http_date(last_modified))?,
+    );
+    let message = signed_packet_to_hickory_message(&signed_packet)?;
+    if let Some(min_ttl) = message.answers().iter().map(|rec| rec.ttl()).min() {
+        headers.insert(
+            header::CACHE_CONTROL,
+            header_value(&format!("public, max-age={min_ttl}"))?,
+        );
+    }
+
+    if is_not_modified(&request_headers, &etag, last_modified) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-pkarr-signed-packet"),
+    );
+    Ok((headers, signed_packet.as_relay_request()).into_response())
+}
+
+/// Check the conditional request headers against a packet's `etag` and modification time.
+///
+/// `If-None-Match` takes precedence over `If-Modified-Since`, see RFC 9110, section 13.2.2.
+fn is_not_modified(headers: &HeaderMap, etag: &str, last_modified: SystemTime) -> bool {
+    if let Some(value) = headers.get(header::IF_NONE_MATCH) {
+        let Ok(value) = value.to_str() else {
+            return false;
+        };
+        return value
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+        .is_some_and(|since| last_modified <= since)
+}
+
+fn header_value(value: &str) -> Result<HeaderValue> {
+    Ok(HeaderValue::from_str(value)?)
+}
+
+/// An entry in the response to [`history`].
+#[derive(Debug, Serialize)]
+pub struct HistoryEntry {
+    /// The pkarr timestamp of the packet, in microseconds since the unix epoch.
+    timestamp: u64,
+    /// The signed packet in the relay format, base64url encoded.
+    packet: String,
+}
+
+pub async fn history(
+    State(state): State<AppState>,
+    Path(pubkey): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let pubkey = PublicKeyBytes::from_z32(&pubkey)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))?;
+    let packets = state.store.history(&pubkey).await?;
+    if packets.is_empty() {
+        return Err(AppError::with_status(StatusCode::NOT_FOUND));
+    }
+    let entries: Vec<_> = packets
+        .iter()
+        .map(|packet| HistoryEntry {
+            timestamp: *packet.timestamp(),
+            packet: base64_url::encode(&packet.as_relay_request()),
+        })
+        .collect();
+    Ok(Json(entries))
+}
+
+/// Stream the signed packets for a pubkey as server-sent events.
+///
+/// The currently stored packet, if any, is sent first, followed by each newer packet as soon as
+/// it is stored. Each `packet` event carries the signed packet in the relay format, base64url
+/// encoded, with the pkarr timestamp as event id.
+///
+/// Answers `503 Service Unavailable` if the configured number of watch streams is open.
+pub async fn watch(
+    State(state): State<AppState>,
+    Path(pubkey): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let pubkey = PublicKeyBytes::from_z32(&pubkey)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))?;
+    let permit = state
+        .watch_permits
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| {
+            AppError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                Some("too many watchers".to_string()),
+            )
+        })?;
+    // Watch before reading the current packet, so that no update is missed in between.
+    let watcher = state.store.watch(&pubkey);
+    let current = state.store.get_signed_packet(&pubkey).await?;
+    let watch = Watch {
+        store: state.store,
+        pubkey,
+        watcher,
+        current,
+        last_timestamp: 0,
+        _permit: permit,
+    };
+    let stream = futures::stream::unfold(watch, |mut watch| async move {
+        let packet = watch.next().await?;
+        let event = Event::default()
+            .event("packet")
+            .id(packet.timestamp().to_string())
+            .data(base64_url::encode(&packet.as_relay_request()));
+        Some((Ok::<_, Infallible>(event), watch))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// State of a [`watch`] stream.
+struct Watch {
+    store: ZoneStore,
+    pubkey: PublicKeyBytes,
+    watcher: PacketWatcher,
+    current: Option<SignedPacket>,
+    last_timestamp: u64,
+    /// Released when the stream is dropped.
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Watch {
+    /// Wait for the next packet that is newer than the last sent one.
+    ///
+    /// Returns `None` if the stream should end.
+    async fn next(&mut self) -> Option<SignedPacket> {
+        if let Some(packet) = self.current.take() {
+            self.last_timestamp = *packet.timestamp();
+            return Some(packet);
+        }
+        loop {
+            let timestamp = self.watcher.changed().await?;
+            if timestamp <= self.last_timestamp {
+                continue;
+            }
+            match self.store.get_signed_packet(&self.pubkey).await {
+                Ok(Some(packet)) if *packet.timestamp() > self.last_timestamp => {
+                    self.last_timestamp = *packet.timestamp();
+                    return Some(packet);
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(?err, "watch: failed to get signed packet");
+                    return None;
+                }
+            }
+        }
+    }
 }
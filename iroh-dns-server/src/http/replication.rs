@@ -0,0 +1,48 @@
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Json,
+};
+use http::{header, HeaderMap, StatusCode};
+use serde::Deserialize;
+
+use super::error::AppError;
+use crate::{
+    replication::{check_token, packets_page, MAX_PAGE_SIZE},
+    state::AppState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct PacketsQuery {
+    /// Sequence number to list packets after, i.e. the cursor of the previous page.
+    after: Option<u64>,
+    /// Maximum number of packets to return.
+    limit: Option<usize>,
+}
+
+pub async fn packets(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<PacketsQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(expected) = state.replication_token.as_deref() else {
+        return Err(AppError::with_status(StatusCode::NOT_FOUND));
+    };
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| check_token(expected, token))
+        .unwrap_or(false);
+    if !authorized {
+        return Err(AppError::with_status(StatusCode::UNAUTHORIZED));
+    }
+    let page = packets_page(
+        &state.store,
+        query.after.unwrap_or(0),
+        query.limit.unwrap_or(MAX_PAGE_SIZE),
+    )
+    .await?;
+    Ok(Json(page))
+}
@@ -50,11 +50,26 @@ impl CertMode {
     }
 }
 
+/// A certificate chain with its secret key.
+pub type CertificateAndKey = (Vec<rustls::Certificate>, rustls::PrivateKey);
+
+/// The TLS material of the HTTPS server, to serve other protocols with the same certificates.
+#[derive(Clone)]
+pub struct TlsMaterial {
+    /// The rustls server config.
+    pub config: Arc<rustls::ServerConfig>,
+    /// The certificate and key, if they are static (i.e. not renewed via ACME).
+    pub certificate: Option<CertificateAndKey>,
+}
+
 /// TLS Certificate Authority acceptor.
+///
+/// Also holds the underlying TLS material, so that other listeners can serve the same
+/// certificates.
 #[derive(Clone)]
 pub enum TlsAcceptor {
-    LetsEncrypt(AxumAcceptor),
-    Manual(RustlsAcceptor),
+    LetsEncrypt(AxumAcceptor, Arc<rustls::ServerConfig>),
+    Manual(RustlsAcceptor, Arc<rustls::ServerConfig>, CertificateAndKey),
 }
 
 impl<I: AsyncRead + AsyncWrite + Unpin + Send + 'static, S: Send + 'static> Accept<I, S>
@@ -66,22 +81,36 @@ impl<I: AsyncRead + AsyncWrite + Unpin + Send + 'static, S: Send + 'static> Acce
 
     fn accept(&self, stream: I, service: S) -> Self::Future {
         match self {
-            Self::LetsEncrypt(a) => a.accept(stream, service).boxed(),
-            Self::Manual(a) => a.accept(stream, service).boxed(),
+            Self::LetsEncrypt(a, _) => a.accept(stream, service).boxed(),
+            Self::Manual(a, _, _) => a.accept(stream, service).boxed(),
         }
     }
 }
 
 impl TlsAcceptor {
+    /// The TLS material of this acceptor.
+    pub fn material(&self) -> TlsMaterial {
+        match self {
+            Self::LetsEncrypt(_, config) => TlsMaterial {
+                config: config.clone(),
+                certificate: None,
+            },
+            Self::Manual(_, config, certificate) => TlsMaterial {
+                config: config.clone(),
+                certificate: Some(certificate.clone()),
+            },
+        }
+    }
+
     async fn self_signed(domains: Vec<String>) -> Result<Self> {
         let tls_cert = rcgen::generate_simple_self_signed(domains)?;
-        let config = RustlsConfig::from_der(
-            vec![tls_cert.serialize_der()?],
-            tls_cert.serialize_private_key_der(),
-        )
-        .await?;
+        let cert = tls_cert.serialize_der()?;
+        let key = tls_cert.serialize_private_key_der();
+        let config = RustlsConfig::from_der(vec![cert.clone()], key.clone()).await?;
+        let server_config = config.get_inner();
         let acceptor = RustlsAcceptor::new(config);
-        Ok(Self::Manual(acceptor))
+        let certificate = (vec![rustls::Certificate(cert)], rustls::PrivateKey(key));
+        Ok(Self::Manual(acceptor, server_config, certificate))
     }
 
     async fn manual(domains: Vec<String>, dir: PathBuf) -> Result<Self> {
@@ -102,10 +131,9 @@ impl TlsAcceptor {
         })
         .await??;
 
-        let config = config.with_single_cert(certs, secret_key)?;
-        let config = RustlsConfig::from_config(Arc::new(config));
-        let acceptor = RustlsAcceptor::new(config);
-        Ok(Self::Manual(acceptor))
+        let config = Arc::new(config.with_single_cert(certs.clone(), secret_key.clone())?);
+        let acceptor = RustlsAcceptor::new(RustlsConfig::from_config(config.clone()));
+        Ok(Self::Manual(acceptor, config, (certs, secret_key)))
     }
 
     fn letsencrypt(
@@ -138,8 +166,8 @@ impl TlsAcceptor {
             .instrument(info_span!("acme")),
         );
         let config = Arc::new(config);
-        let acceptor = AxumAcceptor::new(acceptor, config);
-        Ok(Self::LetsEncrypt(acceptor))
+        let acceptor = AxumAcceptor::new(acceptor, config.clone());
+        Ok(Self::LetsEncrypt(acceptor, config))
     }
 }
 
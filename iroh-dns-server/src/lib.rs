@@ -6,16 +6,33 @@ pub mod config;
 pub mod dns;
 pub mod http;
 pub mod metrics;
+pub mod replication;
 pub mod server;
 pub mod state;
-mod store;
+pub mod store;
+pub mod upstream;
 mod util;
 
+#[cfg(test)]
+mod test_utils;
+
 #[cfg(test)]
 mod tests {
-    use std::net::SocketAddr;
+    use std::{net::SocketAddr, time::Duration};
 
-    use anyhow::Result;
+    use anyhow::{bail, Context, Result};
+    use hickory_proto::{
+        op::{Edns, Message, OpCode, Query, ResponseCode},
+        rr::{
+            dnssec::{
+                rdata::{DNSSECRData, SIG},
+                tbs::message_tbs,
+                Algorithm, Verifier,
+            },
+            rdata::TXT,
+            DNSClass, Name, RData, Record, RecordType,
+        },
+    };
     use hickory_resolver::{
         config::{NameServerConfig, Protocol, ResolverConfig},
         AsyncResolver,
@@ -23,14 +40,21 @@ mod tests {
     use iroh_net::{
         discovery::pkarr_publish::PkarrRelayClient,
         dns::{
-            node_info::{lookup_by_id, NodeInfo},
+            node_info::{lookup_by_id, NodeInfo, IROH_TXT_NAME},
             DnsResolver,
         },
         key::SecretKey,
     };
     use url::Url;
 
-    use crate::server::Server;
+    use crate::{
+        config::{ForwardConfig, ProxyConfig, ReplicationConfig, StoreConfig, WatchConfig},
+        dns::DnssecConfig,
+        server::Server,
+        test_utils::{
+            node_packet, node_packet_with_ttl, publish_random_node_packet, TempPath, RELAY_URL,
+        },
+    };
 
     #[tokio::test]
     async fn integration_smoke() -> Result<()> {
@@ -71,6 +95,491 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn forward_publish() -> Result<()> {
+        let (upstream, _, upstream_url) = Server::spawn_for_tests().await?;
+        let upstream_relay = upstream_url.join("/pkarr")?;
+        let (server, _, http_url) = Server::spawn_for_tests_with_config(|config| {
+            config.forward = Some(ForwardConfig {
+                relays: vec![upstream_relay.to_string()],
+                ..Default::default()
+            });
+        })
+        .await?;
+
+        let (_secret_key, signed_packet) =
+            publish_random_node_packet(http_url.join("/pkarr")?).await?;
+
+        // The packet is forwarded in the background.
+        let url = format!("{upstream_relay}/{}", signed_packet.public_key().to_z32());
+        let forwarded = wait_for_packet(&url).await?;
+        assert_eq!(forwarded, signed_packet.as_relay_request());
+
+        server.shutdown().await?;
+        upstream.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_lookups() -> Result<()> {
+        let (central, _, central_url) = Server::spawn_for_tests().await?;
+        let central_relay = central_url.join("/pkarr")?;
+        let (edge, edge_nameserver, edge_url) = Server::spawn_for_tests_with_config(|config| {
+            config.proxy = Some(ProxyConfig {
+                relay: central_relay.to_string(),
+            });
+        })
+        .await?;
+
+        let (secret_key, signed_packet) = publish_random_node_packet(central_relay).await?;
+        let node_id = secret_key.public();
+        let relay_url: Url = RELAY_URL.parse()?;
+
+        // DNS lookups on the edge are proxied to the central relay.
+        let resolver = test_resolver(edge_nameserver);
+        let resolved = lookup_by_id(&resolver, &node_id, "irohdns.example.").await?;
+        assert_eq!(resolved.node_id, node_id);
+        assert_eq!(resolved.info.relay_url.map(Url::from), Some(relay_url));
+
+        // So are relay lookups.
+        let url = format!("{edge_url}pkarr/{}", signed_packet.public_key().to_z32());
+        let res = reqwest::get(&url).await?;
+        assert!(res.status().is_success());
+        assert_eq!(res.bytes().await?, signed_packet.as_relay_request());
+
+        edge.shutdown().await?;
+        central.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_revalidation() -> Result<()> {
+        let (central, _, central_url) = Server::spawn_for_tests().await?;
+        let central_relay = central_url.join("/pkarr")?;
+        let (edge, _, edge_url) = Server::spawn_for_tests_with_config(|config| {
+            config.proxy = Some(ProxyConfig {
+                relay: central_relay.to_string(),
+            });
+        })
+        .await?;
+        let pkarr = PkarrRelayClient::new(central_relay);
+
+        let secret_key = SecretKey::generate();
+        let v1 = node_packet_with_ttl(&secret_key, 1)?;
+        pkarr.publish(&v1).await?;
+        let url = format!("{edge_url}pkarr/{}", v1.public_key().to_z32());
+        assert_eq!(wait_for_packet(&url).await?, v1.as_relay_request());
+
+        // The edge picks up a newer packet published to the central relay after the TTL of the
+        // packet it stored.
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let v2 = node_packet_with_ttl(&secret_key, 1)?;
+        pkarr.publish(&v2).await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while reqwest::get(&url).await?.bytes().await? != v2.as_relay_request() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+
+        edge.shutdown().await?;
+        central.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replicate_between_servers() -> Result<()> {
+        let token = "secret".to_string();
+        let (a, _, a_url) = Server::spawn_for_tests_with_config(|config| {
+            config.replication = Some(ReplicationConfig {
+                token: token.clone(),
+                ..Default::default()
+            });
+        })
+        .await?;
+        let (b, _, b_url) = Server::spawn_for_tests_with_config(|config| {
+            config.replication = Some(ReplicationConfig {
+                token: token.clone(),
+                peers: vec![a_url.to_string()],
+                interval_ms: Some(100),
+            });
+        })
+        .await?;
+
+        // The replication endpoint requires the token.
+        let res = reqwest::get(a_url.join("/replication/packets")?).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let (_secret_key, signed_packet) =
+            publish_random_node_packet(a_url.join("/pkarr")?).await?;
+
+        // The packet is pulled by b.
+        let url = format!("{b_url}pkarr/{}", signed_packet.public_key().to_z32());
+        let replicated = wait_for_packet(&url).await?;
+        assert_eq!(replicated, signed_packet.as_relay_request());
+
+        a.shutdown().await?;
+        b.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn conditional_get() -> Result<()> {
+        let (server, _, http_url) = Server::spawn_for_tests().await?;
+
+        let (_secret_key, signed_packet) =
+            publish_random_node_packet(http_url.join("/pkarr")?).await?;
+
+        let url = http_url.join(&format!("/pkarr/{}", signed_packet.public_key().to_z32()))?;
+        let client = reqwest::Client::new();
+        let res = client.get(url.clone()).send().await?;
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let headers = res.headers();
+        assert_eq!(
+            headers[reqwest::header::CACHE_CONTROL],
+            "public, max-age=30"
+        );
+        let etag = headers[reqwest::header::ETAG].clone();
+        let last_modified = headers[reqwest::header::LAST_MODIFIED].clone();
+
+        let res = client
+            .get(url.clone())
+            .header(reqwest::header::IF_NONE_MATCH, etag)
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_MODIFIED);
+        let res = client
+            .get(url.clone())
+            .header(reqwest::header::IF_MODIFIED_SINCE, last_modified)
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_MODIFIED);
+        let res = client
+            .get(url)
+            .header(reqwest::header::IF_NONE_MATCH, "\"0\"")
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn packet_history() -> Result<()> {
+        let (server, _, http_url) = Server::spawn_for_tests_with_config(|config| {
+            config.store = Some(StoreConfig {
+                history_size: Some(1),
+                ..Default::default()
+            });
+        })
+        .await?;
+
+        let secret_key = SecretKey::generate();
+        let key = z32::encode(secret_key.public().as_bytes());
+        let pkarr = PkarrRelayClient::new(http_url.join("/pkarr")?);
+        let mut timestamps = vec![];
+        // Publishes are rate limited to bursts of two.
+        for _ in 0..2 {
+            let signed_packet = node_packet(&secret_key)?;
+            timestamps.push(*signed_packet.timestamp());
+            pkarr.publish(&signed_packet).await?;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // Only the most recent packet is kept.
+        let url = http_url.join(&format!("/pkarr/{key}/history"))?;
+        #[derive(serde::Deserialize)]
+        struct Entry {
+            timestamp: u64,
+        }
+        let history: Vec<Entry> = reqwest::get(url).await?.json().await?;
+        let history: Vec<_> = history.iter().map(|entry| entry.timestamp).collect();
+        assert_eq!(history, vec![timestamps[1]]);
+
+        let missing = z32::encode(SecretKey::generate().public().as_bytes());
+        let url = http_url.join(&format!("/pkarr/{missing}/history"))?;
+        assert_eq!(
+            reqwest::get(url).await?.status(),
+            reqwest::StatusCode::NOT_FOUND
+        );
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_packets() -> Result<()> {
+        let (server, _, http_url) = Server::spawn_for_tests().await?;
+
+        let secret_key = SecretKey::generate();
+        let key = z32::encode(secret_key.public().as_bytes());
+        let url = http_url.join(&format!("/pkarr/{key}/watch"))?;
+        let mut res = reqwest::get(url).await?;
+        assert!(res.status().is_success());
+
+        let signed_packet = node_packet(&secret_key)?;
+        let pkarr = PkarrRelayClient::new(http_url.join("/pkarr")?);
+        pkarr.publish(&signed_packet).await?;
+
+        // The published packet is streamed as an event.
+        let expected = format!(
+            "data: {}\n",
+            base64_url::encode(&signed_packet.as_relay_request())
+        );
+        let mut received = String::new();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !received.contains(&expected) {
+                let chunk = res.chunk().await?.context("stream closed")?;
+                received.push_str(std::str::from_utf8(&chunk)?);
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_limit() -> Result<()> {
+        let (server, _, http_url) = Server::spawn_for_tests_with_config(|config| {
+            config.watch = Some(WatchConfig {
+                max_watchers: Some(1),
+            });
+        })
+        .await?;
+
+        let secret_key = SecretKey::generate();
+        let key = z32::encode(secret_key.public().as_bytes());
+        let url = http_url.join(&format!("/pkarr/{key}/watch"))?;
+        let res = reqwest::get(url.clone()).await?;
+        assert!(res.status().is_success());
+
+        // The second watcher is refused while the first stream is open.
+        let refused = reqwest::get(url).await?;
+        assert_eq!(refused.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+        drop(res);
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dnssec_signed_answers() -> Result<()> {
+        let origin = Name::from_utf8("irohdns.example.")?;
+        let key_path = TempPath::new("key");
+        let (server, nameserver, http_url) = Server::spawn_for_tests_with_config(|config| {
+            config.dns.dnssec = Some(DnssecConfig {
+                keys: [(origin.to_string(), key_path.to_path_buf())].into(),
+                ..Default::default()
+            });
+        })
+        .await?;
+
+        let (_secret_key, signed_packet) =
+            publish_random_node_packet(http_url.join("/pkarr")?).await?;
+
+        let response = dnssec_query(nameserver, &origin, RecordType::DNSKEY).await?;
+        let dnskey = response
+            .answers()
+            .iter()
+            .find_map(|record| match record.data() {
+                Some(RData::DNSSEC(DNSSECRData::DNSKEY(dnskey))) => Some(dnskey.clone()),
+                _ => None,
+            })
+            .context("missing DNSKEY")?;
+
+        // The pkarr record set is signed with the key of the origin.
+        let name = Name::parse(
+            &format!("{IROH_TXT_NAME}.{}", signed_packet.public_key().to_z32()),
+            Some(&origin),
+        )?;
+        let response = dnssec_query(nameserver, &name, RecordType::TXT).await?;
+        let (rrsigs, records): (Vec<_>, Vec<_>) = response
+            .answers()
+            .iter()
+            .cloned()
+            .partition(|record| record.record_type() == RecordType::RRSIG);
+        let Some(RData::DNSSEC(DNSSECRData::RRSIG(rrsig))) = rrsigs.first().and_then(|r| r.data())
+        else {
+            bail!("missing RRSIG");
+        };
+        dnskey.verify_rrsig(&name, DNSClass::IN, rrsig, &records)?;
+
+        // A missing record type at an existing name is denied with a signed NSEC record.
+        let response = dnssec_query(nameserver, &name, RecordType::A).await?;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        let nsec_types = |response: &Message| -> Vec<RecordType> {
+            response
+                .name_servers()
+                .iter()
+                .map(|record| record.record_type())
+                .collect()
+        };
+        assert!(nsec_types(&response).contains(&RecordType::NSEC));
+        assert!(nsec_types(&response).contains(&RecordType::RRSIG));
+
+        // A missing name is denied with NSEC records covering only the name itself.
+        let missing = Name::parse("missing", Some(&name.base_name()))?;
+        let response = dnssec_query(nameserver, &missing, RecordType::TXT).await?;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        let covers_missing = response.name_servers().iter().any(|record| {
+            matches!(
+                record.data(),
+                Some(RData::DNSSEC(DNSSECRData::NSEC(nsec)))
+                    if record.name() < &missing && &missing < nsec.next_domain_name()
+            )
+        });
+        assert!(covers_missing);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dns_update() -> Result<()> {
+        let (server, nameserver, http_url) = Server::spawn_for_tests().await?;
+        let origin = Name::from_utf8("irohdns.example.")?;
+
+        let secret_key = SecretKey::generate();
+        let key = z32::encode(secret_key.public().as_bytes());
+        let zone = Name::parse(&key, Some(&origin))?;
+        let name = Name::parse("test", Some(&zone))?;
+        let txt = |text: &str| {
+            let mut record = Record::from_rdata(
+                name.clone(),
+                30,
+                RData::TXT(TXT::new(vec![text.to_string()])),
+            );
+            record.set_dns_class(DNSClass::IN);
+            record
+        };
+        let query_txt = || async {
+            let mut query = Message::new();
+            query.add_query(Query::query(name.clone(), RecordType::TXT));
+            let response = dns_exchange(nameserver, query).await?;
+            let texts: Vec<_> = response
+                .answers()
+                .iter()
+                .filter_map(|record| match record.data() {
+                    Some(RData::TXT(txt)) => Some(txt.to_string()),
+                    _ => None,
+                })
+                .collect();
+            anyhow::Ok(texts)
+        };
+
+        // Updates must be signed by the key of the zone.
+        let update = signed_update(&SecretKey::generate(), &zone, txt("hello"))?;
+        let response = dns_exchange(nameserver, update).await?;
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+
+        let relay = PkarrRelayClient::new(http_url.join("/pkarr")?);
+        let packet = node_packet(&secret_key)?;
+        relay.publish(&packet).await?;
+
+        let update = signed_update(&secret_key, &zone, txt("hello"))?;
+        let response = dns_exchange(nameserver, update).await?;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(query_txt().await?, vec!["hello".to_string()]);
+        // The records of the signed packet are kept.
+        let resolver = test_resolver(nameserver);
+        let resolved = lookup_by_id(&resolver, &secret_key.public(), "irohdns.example.").await?;
+        assert_eq!(resolved.node_id, secret_key.public());
+
+        // Further updates apply on top of the previous one.
+        let update = signed_update(&secret_key, &zone, txt("world"))?;
+        let response = dns_exchange(nameserver, update).await?;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        let mut texts = query_txt().await?;
+        texts.sort();
+        assert_eq!(texts, vec!["hello".to_string(), "world".to_string()]);
+
+        // The updated zone has no pkarr signature, the relay keeps serving the signed packet.
+        let url = http_url.join(&format!("/pkarr/{key}"))?;
+        let body = reqwest::get(url).await?.error_for_status()?.bytes().await?;
+        assert_eq!(body, packet.as_relay_request());
+
+        // A newer packet published to the relay replaces the updated zone.
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        relay.publish(&node_packet(&secret_key)?).await?;
+        assert!(query_txt().await?.is_empty());
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    /// Create a DNS UPDATE that adds `record` to `zone`, signed with SIG(0) by `secret_key`.
+    fn signed_update(secret_key: &SecretKey, zone: &Name, record: Record) -> Result<Message> {
+        let mut message = Message::new();
+        message.set_op_code(OpCode::Update);
+        message.add_query(Query::query(zone.clone(), RecordType::SOA));
+        message.add_name_server(record);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs() as u32;
+        let sig = SIG::new(
+            RecordType::ZERO,
+            Algorithm::ED25519,
+            0,
+            0,
+            now + 300,
+            now,
+            0,
+            zone.clone(),
+            vec![],
+        );
+        let tbs = message_tbs(&message, &sig)?;
+        let signature = secret_key.sign(tbs.as_ref()).to_bytes().to_vec();
+        let rdata = RData::DNSSEC(DNSSECRData::SIG(sig.set_sig(signature)));
+        let mut sig0 = Record::from_rdata(Name::root(), 0, rdata);
+        sig0.set_dns_class(DNSClass::ANY);
+        message.add_sig0(sig0);
+        Ok(message)
+    }
+
+    /// Send a DNS query over UDP with the DNSSEC OK bit set.
+    async fn dnssec_query(
+        nameserver: SocketAddr,
+        name: &Name,
+        record_type: RecordType,
+    ) -> Result<Message> {
+        let mut message = Message::new();
+        message.add_query(Query::query(name.clone(), record_type));
+        let mut edns = Edns::new();
+        edns.set_dnssec_ok(true);
+        edns.set_max_payload(4096);
+        message.set_edns(edns);
+        dns_exchange(nameserver, message).await
+    }
+
+    /// Send a DNS message over UDP and wait for the response.
+    async fn dns_exchange(nameserver: SocketAddr, message: Message) -> Result<Message> {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        socket.send_to(&message.to_vec()?, nameserver).await?;
+        let mut buf = vec![0u8; 4096];
+        let (len, _) =
+            tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf)).await??;
+        Ok(Message::from_vec(&buf[..len])?)
+    }
+
+    /// Poll a pkarr relay url until it returns a packet.
+    async fn wait_for_packet(url: &str) -> Result<bytes::Bytes> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let res = reqwest::get(url).await?;
+                if res.status().is_success() {
+                    break anyhow::Ok(res.bytes().await?);
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await?
+    }
+
     fn test_resolver(nameserver: SocketAddr) -> DnsResolver {
         let mut config = ResolverConfig::new();
         let nameserver_config = NameServerConfig::new(nameserver, Protocol::Udp);
@@ -2,10 +2,12 @@
 
 use anyhow::Result;
 use axum::{routing::get, Router};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use futures::{Future, FutureExt};
 use iroh_dns_server::{
-    config::Config, metrics::init_metrics, server::run_with_config_until_ctrl_c,
+    config::Config,
+    metrics::init_metrics,
+    server::{export_store, import_store, print_ds_records, run_with_config_until_ctrl_c},
 };
 use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
@@ -18,6 +20,31 @@ struct Cli {
     /// Path to config file
     #[clap(short, long)]
     config: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Export all signed packets from the store to a dump file.
+    ///
+    /// The server must not be running while exporting.
+    Export {
+        /// Path of the dump file to create
+        path: PathBuf,
+    },
+    /// Import signed packets from a dump file into the store.
+    ///
+    /// Packets older than the stored packet for their public key are skipped.
+    /// The server must not be running while importing.
+    Import {
+        /// Path of the dump file to read
+        path: PathBuf,
+    },
+    /// Print the DS records to publish in the parent zones of origins with DNSSEC enabled.
+    ///
+    /// Signing keys that don't exist yet are generated.
+    Ds,
 }
 
 #[tokio::main]
@@ -31,6 +58,13 @@ async fn main() -> Result<()> {
         Config::default()
     };
 
-    init_metrics();
-    run_with_config_until_ctrl_c(config).await
+    match args.command {
+        None => {
+            init_metrics();
+            run_with_config_until_ctrl_c(config).await
+        }
+        Some(Command::Export { path }) => export_store(&config, path).await,
+        Some(Command::Import { path }) => import_store(&config, path).await,
+        Some(Command::Ds) => print_ds_records(&config),
+    }
 }
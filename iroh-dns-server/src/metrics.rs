@@ -1,7 +1,15 @@
 //! Metrics support for the server
 
+use std::sync::OnceLock;
+
 use iroh_metrics::core::{Core, Counter, Metric};
+use prometheus_client::{
+    encoding::EncodeLabelSet,
+    metrics::{counter::Counter as LabeledCounter, family::Family},
+    registry::Registry,
+};
 use struct_iterable::Iterable;
+use url::Url;
 
 /// Metrics for iroh-dns-server
 #[derive(Debug, Clone, Iterable)]
@@ -13,6 +21,7 @@ pub struct Metrics {
     pub dns_requests: Counter,
     pub dns_requests_udp: Counter,
     pub dns_requests_https: Counter,
+    pub dns_requests_quic: Counter,
     pub dns_lookup_success: Counter,
     pub dns_lookup_notfound: Counter,
     pub dns_lookup_error: Counter,
@@ -23,6 +32,21 @@ pub struct Metrics {
     pub store_packets_inserted: Counter,
     pub store_packets_removed: Counter,
     pub store_packets_updated: Counter,
+    pub store_write_batches: Counter,
+    pub store_cache_hits: Counter,
+    pub store_cache_negative_hits: Counter,
+    pub store_cache_misses: Counter,
+    pub store_cache_evictions: Counter,
+    pub upstream_resolve_success: Counter,
+    pub upstream_resolve_notfound: Counter,
+    pub upstream_resolve_error: Counter,
+    pub upstream_revalidations: Counter,
+    pub upstream_republish_success: Counter,
+    pub upstream_republish_error: Counter,
+    pub relay_forward_success: Counter,
+    pub relay_forward_error: Counter,
+    pub replication_packets_updated: Counter,
+    pub replication_packets_invalid: Counter,
 }
 
 impl Default for Metrics {
@@ -36,6 +60,7 @@ impl Default for Metrics {
             dns_requests: Counter::new("DNS requests (total)"),
             dns_requests_udp: Counter::new("DNS requests via UDP"),
             dns_requests_https: Counter::new("DNS requests via HTTPS (DoH)"),
+            dns_requests_quic: Counter::new("DNS requests via QUIC (DoQ)"),
             dns_lookup_success: Counter::new("DNS lookup responses with at least one answer"),
             dns_lookup_notfound: Counter::new("DNS lookup responses with no answers"),
             dns_lookup_error: Counter::new("DNS lookup responses which failed"),
@@ -46,6 +71,31 @@ impl Default for Metrics {
             store_packets_inserted: Counter::new("Signed packets inserted into the store"),
             store_packets_removed: Counter::new("Signed packets removed from the store"),
             store_packets_updated: Counter::new("Number of updates to existing packets"),
+            store_write_batches: Counter::new("Write transactions for batches of packet upserts"),
+            store_cache_hits: Counter::new("Zone lookups answered from the in-memory cache"),
+            store_cache_negative_hits: Counter::new(
+                "Zone lookups answered from the cache of pubkeys without a packet",
+            ),
+            store_cache_misses: Counter::new("Zone lookups that missed the in-memory cache"),
+            store_cache_evictions: Counter::new("Zones evicted from the in-memory cache"),
+            upstream_resolve_success: Counter::new("Local misses resolved from upstream"),
+            upstream_resolve_notfound: Counter::new("Local misses not found upstream either"),
+            upstream_resolve_error: Counter::new("Upstream resolves which failed"),
+            upstream_revalidations: Counter::new(
+                "Packets resolved from upstream again after their minimum TTL",
+            ),
+            upstream_republish_success: Counter::new("Stored packets republished upstream"),
+            upstream_republish_error: Counter::new("Republishes of stored packets which failed"),
+            relay_forward_success: Counter::new("Packets forwarded to upstream pkarr relays"),
+            relay_forward_error: Counter::new(
+                "Packets which failed to be forwarded to upstream pkarr relays after all retries",
+            ),
+            replication_packets_updated: Counter::new(
+                "Packets and zone updates pulled from peer instances which updated the store",
+            ),
+            replication_packets_invalid: Counter::new(
+                "Packets and zone updates pulled from peer instances which were skipped because they were invalid",
+            ),
         }
     }
 }
@@ -56,9 +106,71 @@ impl Metric for Metrics {
     }
 }
 
+/// Labels of the [`UpstreamMetrics`].
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct UpstreamLabels {
+    /// The URL of the upstream.
+    pub upstream: String,
+}
+
+/// Metrics for each upstream pkarr relay.
+///
+/// These are labeled with the upstream URL, which [`Metrics`] doesn't support. They are
+/// registered with the same prefix in [`init_metrics`].
+#[derive(Debug, Default)]
+pub struct UpstreamMetrics {
+    /// Packets forwarded to the upstream.
+    pub forward_success: Family<UpstreamLabels, LabeledCounter>,
+    /// Packets which failed to be forwarded to the upstream after all retries.
+    pub forward_error: Family<UpstreamLabels, LabeledCounter>,
+}
+
+impl UpstreamMetrics {
+    /// Get the global upstream metrics.
+    pub fn get() -> &'static Self {
+        static METRICS: OnceLock<UpstreamMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    pub(crate) fn forward_success(&self, upstream: &Url) {
+        self.forward_success
+            .get_or_create(&UpstreamLabels::new(upstream))
+            .inc();
+    }
+
+    pub(crate) fn forward_error(&self, upstream: &Url) {
+        self.forward_error
+            .get_or_create(&UpstreamLabels::new(upstream))
+            .inc();
+    }
+
+    fn register(&self, registry: &mut Registry) {
+        let registry = registry.sub_registry_with_prefix(Metrics::name());
+        registry.register(
+            "relay_forward_success_by_upstream",
+            "Packets forwarded to an upstream pkarr relay",
+            self.forward_success.clone(),
+        );
+        registry.register(
+            "relay_forward_error_by_upstream",
+            "Packets which failed to be forwarded to an upstream pkarr relay after all retries",
+            self.forward_error.clone(),
+        );
+    }
+}
+
+impl UpstreamLabels {
+    fn new(upstream: &Url) -> Self {
+        Self {
+            upstream: upstream.to_string(),
+        }
+    }
+}
+
 /// Init the metrics collection core.
 pub fn init_metrics() {
     Core::init(|reg, metrics| {
         metrics.insert(Metrics::new(reg));
+        UpstreamMetrics::get().register(reg);
     });
 }
@@ -0,0 +1,529 @@
+//! Replication of signed packets and zone updates between iroh-dns-server instances.
+//!
+//! Each instance with replication enabled serves its packets at `GET /replication/packets`,
+//! authenticated with a shared bearer token. Packets are ordered by the sequence number at which
+//! the instance stored them, see [`ZoneStore::updated_after`]. Instances pull new packets from
+//! their peers periodically and apply them with [`ZoneStore::insert`], so that only packets newer
+//! than the stored ones take effect.
+//!
+//! Zones changed with a DNS UPDATE are listed along with the signed packet they are based on, see
+//! [`ZoneUpdate`]. A pulled zone update only takes effect if it applies to the stored packet and
+//! is newer than the stored zone update.
+//!
+//! The sequence number up to which each peer was replicated is kept as a cursor in the store's
+//! metadata, so that an instance catches up after downtime without a full rescan. The cursor
+//! doesn't depend on the pkarr timestamps chosen by the publishers, so packets with old
+//! timestamps that arrive late at a peer are replicated as well.
+
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use hickory_proto::op::Message;
+use iroh_metrics::{inc, inc_by};
+use pkarr::SignedPacket;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::{
+    metrics::Metrics,
+    store::{PacketSource, UpsertOutcome, ZoneStore, ZoneUpdate, MAX_ZONE_UPDATE_SIZE},
+    util::PublicKeyBytes,
+};
+
+/// Pull packets from peers every 5 seconds by default
+pub const DEFAULT_REPLICATION_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Serve at most 1000 packets per page
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Timeout for requests to peers.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options for replication between instances.
+#[derive(Debug, Clone)]
+pub struct ReplicationOptions {
+    /// Shared secret that peers present as bearer token.
+    pub token: String,
+    /// Base URLs of the peers to pull packets from, e.g. `https://peer.example`.
+    ///
+    /// The replication endpoint is appended to the full path, i.e. packets are pulled from
+    /// `https://peer.example/dns/replication/packets` for `https://peer.example/dns`.
+    pub peers: Vec<Url>,
+    /// Interval between pulls.
+    pub interval: Duration,
+}
+
+/// A page of packets served by the replication endpoint.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PacketsPage {
+    /// The packets, ordered by the sequence number at which the serving instance stored them.
+    pub packets: Vec<PacketEntry>,
+    /// The zone updates that apply to the listed packets, or to no packet.
+    #[serde(default)]
+    pub zone_updates: Vec<ZoneUpdateEntry>,
+    /// The sequence number of the last listed packet, or the requested `after` if the page is
+    /// empty.
+    ///
+    /// The next page starts after this sequence number.
+    pub cursor: u64,
+    /// Whether there are more packets after [`Self::cursor`].
+    pub more: bool,
+}
+
+/// A signed packet in a [`PacketsPage`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PacketEntry {
+    /// The z-base-32 encoded pubkey.
+    pub key: String,
+    /// The signed packet in the relay format, base64url encoded.
+    pub packet: String,
+}
+
+impl PacketEntry {
+    /// Encode a signed packet.
+    pub fn new(packet: &SignedPacket) -> Self {
+        Self {
+            key: packet.public_key().to_z32(),
+            packet: base64_url::encode(&packet.as_relay_request()),
+        }
+    }
+
+    /// Decode the signed packet and verify its signature.
+    pub fn decode(&self) -> Result<SignedPacket> {
+        let key = pkarr::PublicKey::try_from(self.key.as_str())?;
+        let bytes = base64_url::decode(&self.packet)?;
+        let packet = SignedPacket::from_relay_response(key, bytes.into())?;
+        Ok(packet)
+    }
+}
+
+/// A zone update in a [`PacketsPage`], see [`ZoneUpdate`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ZoneUpdateEntry {
+    /// The z-base-32 encoded pubkey.
+    pub key: String,
+    /// The pkarr timestamp of the signed packet the update applies to, or 0.
+    pub base_timestamp: u64,
+    /// When the update was applied, in microseconds since the unix epoch.
+    pub timestamp: u64,
+    /// The encoded DNS packet with all records of the zone, base64url encoded.
+    pub zone: String,
+}
+
+impl ZoneUpdateEntry {
+    /// Encode a zone update.
+    pub fn new(update: &ZoneUpdate) -> Self {
+        Self {
+            key: update.pubkey.to_z32(),
+            base_timestamp: update.base_timestamp,
+            timestamp: update.timestamp,
+            zone: base64_url::encode(&update.encoded_packet),
+        }
+    }
+
+    /// Decode the zone update and check that it is a DNS packet of at most
+    /// [`MAX_ZONE_UPDATE_SIZE`] bytes.
+    ///
+    /// Zone updates carry no signature, so they are only as trustworthy as the peer.
+    pub fn decode(&self) -> Result<ZoneUpdate> {
+        let pubkey = PublicKeyBytes::from_z32(&self.key)?;
+        let bytes = base64_url::decode(&self.zone)?;
+        ensure!(bytes.len() <= MAX_ZONE_UPDATE_SIZE, "zone update too large");
+        Message::from_vec(&bytes)?;
+        Ok(ZoneUpdate {
+            pubkey,
+            base_timestamp: self.base_timestamp,
+            timestamp: self.timestamp,
+            encoded_packet: bytes.into(),
+        })
+    }
+}
+
+/// Check a bearer token against the configured token in constant time.
+pub(crate) fn check_token(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Pulls packets from peers into a [`ZoneStore`].
+#[derive(Debug, Clone)]
+pub struct Replicator {
+    store: ZoneStore,
+    options: ReplicationOptions,
+    http: reqwest::Client,
+}
+
+impl Replicator {
+    /// Create a new replicator.
+    pub fn new(store: ZoneStore, mut options: ReplicationOptions) -> Result<Self> {
+        for peer in options.peers.iter_mut() {
+            // Without a trailing slash, `Url::join` would replace the last path segment.
+            if !peer.path().ends_with('/') {
+                let path = format!("{}/", peer.path());
+                peer.set_path(&path);
+            }
+        }
+        let http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            store,
+            options,
+            http,
+        })
+    }
+
+    /// Spawn a task that periodically pulls packets from all peers.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(self.options.interval);
+            loop {
+                interval.tick().await;
+                for peer in self.options.peers.iter() {
+                    match self.pull(peer).await {
+                        Ok(count) => debug!(%peer, count, "replication: pulled packets"),
+                        Err(err) => warn!(%peer, ?err, "replication: failed to pull packets"),
+                    }
+                }
+            }
+        })
+    }
+
+    /// Pull all packets and zone updates from a peer that were updated since the last pull.
+    ///
+    /// Packets that fail to verify are skipped, so that they don't block the replication of the
+    /// following packets. Returns the number of packets and zone updates that updated the store.
+    pub async fn pull(&self, peer: &Url) -> Result<usize> {
+        let cursor_key = cursor_key(peer);
+        let mut cursor = self.store.metadata(&cursor_key).await?.unwrap_or(0);
+        let mut updated = 0;
+        loop {
+            let page = self.fetch_page(peer, cursor).await?;
+            let mut inserts = Vec::with_capacity(page.packets.len());
+            for entry in page.packets.iter() {
+                match entry.decode() {
+                    Ok(packet) => {
+                        inserts.push(self.store.insert(packet, PacketSource::Replication))
+                    }
+                    Err(err) => {
+                        warn!(%peer, key = %entry.key, ?err, "replication: skipping invalid packet");
+                        inc!(Metrics, replication_packets_invalid);
+                    }
+                }
+            }
+            // Insert concurrently, so that the inserts are committed in batches.
+            let results = futures::future::try_join_all(inserts).await?;
+            updated += results.into_iter().filter(|updated| *updated).count();
+            // Zone updates go after the packets, because they only apply on top of them.
+            for entry in page.zone_updates.iter() {
+                match entry.decode() {
+                    Ok(update) => {
+                        if self.apply_zone_update(update).await? {
+                            updated += 1;
+                        }
+                    }
+                    Err(err) => {
+                        warn!(%peer, key = %entry.key, ?err, "replication: skipping invalid zone update");
+                        inc!(Metrics, replication_packets_invalid);
+                    }
+                }
+            }
+            cursor = page.cursor;
+            // Save the cursor after each page, so that an interrupted pull isn't repeated.
+            self.store.set_metadata(&cursor_key, cursor).await?;
+            if !page.more {
+                break;
+            }
+        }
+        inc_by!(Metrics, replication_packets_updated, updated as u64);
+        Ok(updated)
+    }
+
+    /// Store a zone update if it applies to the stored packet and is newer than the stored zone
+    /// update.
+    async fn apply_zone_update(&self, update: ZoneUpdate) -> Result<bool> {
+        let packet = self.store.get_signed_packet(&update.pubkey).await?;
+        if !update.applies_to(packet.as_ref()) {
+            return Ok(false);
+        }
+        let current = self
+            .store
+            .get_zone_update(&update.pubkey)
+            .await?
+            .filter(|current| current.applies_to(packet.as_ref()))
+            .map(|current| current.timestamp);
+        if current.is_some_and(|current| current >= update.timestamp) {
+            return Ok(false);
+        }
+        let outcome = self
+            .store
+            .insert_zone_update(update, current, PacketSource::Replication)
+            .await?;
+        // A precondition failure means that the store changed in the meantime.
+        Ok(outcome == UpsertOutcome::Stored)
+    }
+
+    async fn fetch_page(&self, peer: &Url, after: u64) -> Result<PacketsPage> {
+        let mut url = peer.join("replication/packets")?;
+        url.query_pairs_mut()
+            .append_pair("after", &after.to_string());
+        let res = self
+            .http
+            .get(url)
+            .bearer_auth(&self.options.token)
+            .send()
+            .await?;
+        let status = res.status();
+        ensure!(
+            status == reqwest::StatusCode::OK,
+            "peer responded with {status}"
+        );
+        Ok(res.json().await?)
+    }
+}
+
+/// The metadata key of the replication cursor for a peer, see [`PacketsPage::cursor`].
+fn cursor_key(peer: &Url) -> String {
+    format!("replication-cursor:{peer}")
+}
+
+/// Serve a page of packets and zone updates stored after the sequence number `after`, see
+/// [`PacketsPage`].
+pub(crate) async fn packets_page(
+    store: &ZoneStore,
+    after: u64,
+    limit: usize,
+) -> Result<PacketsPage> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    let keys = store.updated_after(after, limit).await?;
+    let cursor = keys.last().map_or(after, |(sequence, _key)| *sequence);
+    let more = keys.len() == limit;
+    let mut packets = Vec::with_capacity(keys.len());
+    let mut zone_updates = vec![];
+    for (_sequence, key) in keys {
+        // The packet might have been updated or removed in the meantime.
+        let packet = store.get_signed_packet(&key).await?;
+        if let Some(update) = store.get_zone_update(&key).await? {
+            if update.applies_to(packet.as_ref()) {
+                zone_updates.push(ZoneUpdateEntry::new(&update));
+            }
+        }
+        if let Some(packet) = packet {
+            packets.push(PacketEntry::new(&packet));
+        }
+    }
+    Ok(PacketsPage {
+        packets,
+        zone_updates,
+        cursor,
+        more,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use iroh_net::discovery::pkarr_publish::PkarrRelayClient;
+
+    use super::*;
+    use crate::{
+        config::ReplicationConfig,
+        server::Server,
+        test_utils::{
+            forge_packet, metrics, node_packet_with_timestamp, random_node_packet, TempPath,
+        },
+    };
+
+    fn options(peer: &Url) -> ReplicationOptions {
+        ReplicationOptions {
+            token: "secret".to_string(),
+            peers: vec![peer.clone()],
+            interval: DEFAULT_REPLICATION_INTERVAL,
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_peer_path() -> Result<()> {
+        let store = ZoneStore::in_memory()?;
+        let peer: Url = "https://peer.example/dns".parse()?;
+        let replicator = Replicator::new(store, options(&peer))?;
+        let url = replicator.options.peers[0].join("replication/packets")?;
+        assert_eq!(url.as_str(), "https://peer.example/dns/replication/packets");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn catch_up_after_downtime() -> Result<()> {
+        let (peer, _, peer_url) = Server::spawn_for_tests_with_config(|config| {
+            config.replication = Some(ReplicationConfig {
+                token: "secret".to_string(),
+                ..Default::default()
+            });
+        })
+        .await?;
+        let pkarr = PkarrRelayClient::new(peer_url.join("/pkarr")?);
+        let path = TempPath::new("redb");
+        let cursor_key = cursor_key(&peer_url);
+
+        let (_, first) = random_node_packet()?;
+        pkarr.publish(&first).await?;
+        {
+            let store = ZoneStore::persistent(&path, Default::default())?;
+            let replicator = Replicator::new(store.clone(), options(&peer_url))?;
+            assert_eq!(replicator.pull(&peer_url).await?, 1);
+            let cursor = store.metadata(&cursor_key).await?;
+            assert_eq!(cursor, Some(1));
+        }
+
+        // While this instance is down, another packet is published to the peer.
+        let (_, second) = random_node_packet()?;
+        pkarr.publish(&second).await?;
+
+        // The database is closed once the writer thread of the previous store stopped.
+        let store = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match ZoneStore::persistent(&path, Default::default()) {
+                    Ok(store) => break store,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await?;
+        let cursor = store.metadata(&cursor_key).await?;
+        assert_eq!(cursor, Some(1));
+
+        // Only the new packet updates the store, and the cursor moves past it.
+        let replicator = Replicator::new(store.clone(), options(&peer_url))?;
+        assert_eq!(replicator.pull(&peer_url).await?, 1);
+        let key = PublicKeyBytes::from_signed_packet(&second);
+        assert!(store.get_signed_packet(&key).await?.is_some());
+        let cursor = store.metadata(&cursor_key).await?;
+        assert_eq!(cursor, Some(2));
+
+        drop((replicator, store));
+        peer.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_invalid_packets() -> Result<()> {
+        let metrics = metrics();
+        // The peer holds a packet whose signature doesn't verify between two valid packets.
+        let peer_store = ZoneStore::in_memory()?;
+        let (_, first) = random_node_packet()?;
+        let (_, forged) = random_node_packet()?;
+        let forged = forge_packet(&forged)?;
+        let (_, last) = random_node_packet()?;
+        for packet in [first, forged, last] {
+            peer_store.insert(packet, PacketSource::Import).await?;
+        }
+        let (peer, _, peer_url) = Server::spawn_for_tests_with_store(peer_store, |config| {
+            config.replication = Some(ReplicationConfig {
+                token: "secret".to_string(),
+                ..Default::default()
+            });
+        })
+        .await?;
+
+        // The forged packet is skipped, and the cursor moves past it.
+        let store = ZoneStore::in_memory()?;
+        let replicator = Replicator::new(store.clone(), options(&peer_url))?;
+        let invalid = metrics.replication_packets_invalid.get();
+        assert_eq!(replicator.pull(&peer_url).await?, 2);
+        assert!(metrics.replication_packets_invalid.get() > invalid);
+        assert_eq!(store.metadata(&cursor_key(&peer_url)).await?, Some(3));
+        assert_eq!(replicator.pull(&peer_url).await?, 0);
+
+        peer.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replicate_zone_updates() -> Result<()> {
+        let peer_store = ZoneStore::in_memory()?;
+        let (_, packet) = random_node_packet()?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+        let update = ZoneUpdate {
+            pubkey: pubkey.clone(),
+            base_timestamp: *packet.timestamp(),
+            timestamp: 1,
+            encoded_packet: Message::new().to_vec()?.into(),
+        };
+        peer_store.insert(packet, PacketSource::Import).await?;
+        peer_store
+            .insert_zone_update(update.clone(), None, PacketSource::DnsUpdate)
+            .await?;
+        let (peer, _, peer_url) =
+            Server::spawn_for_tests_with_store(peer_store.clone(), |config| {
+                config.replication = Some(ReplicationConfig {
+                    token: "secret".to_string(),
+                    ..Default::default()
+                });
+            })
+            .await?;
+
+        // The zone update is applied on top of the replicated packet.
+        let store = ZoneStore::in_memory()?;
+        let mut changes = store.subscribe();
+        let replicator = Replicator::new(store.clone(), options(&peer_url))?;
+        assert_eq!(replicator.pull(&peer_url).await?, 2);
+        assert_eq!(store.get_zone_update(&pubkey).await?, Some(update.clone()));
+        assert_eq!(changes.recv().await?.source, PacketSource::Replication);
+        let change = changes.recv().await?;
+        assert_eq!(change.timestamp, 1);
+        assert_eq!(change.source, PacketSource::Replication);
+
+        // A later update at the peer is pulled on its own.
+        let next = ZoneUpdate {
+            timestamp: 2,
+            ..update
+        };
+        peer_store
+            .insert_zone_update(next.clone(), Some(1), PacketSource::DnsUpdate)
+            .await?;
+        assert_eq!(replicator.pull(&peer_url).await?, 1);
+        assert_eq!(store.get_zone_update(&pubkey).await?, Some(next));
+        assert_eq!(replicator.pull(&peer_url).await?, 0);
+
+        peer.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replicate_packets_with_old_timestamps() -> Result<()> {
+        let (peer, _, peer_url) = Server::spawn_for_tests_with_config(|config| {
+            config.replication = Some(ReplicationConfig {
+                token: "secret".to_string(),
+                ..Default::default()
+            });
+        })
+        .await?;
+        let pkarr = PkarrRelayClient::new(peer_url.join("/pkarr")?);
+        let store = ZoneStore::in_memory()?;
+        let replicator = Replicator::new(store.clone(), options(&peer_url))?;
+
+        let (_, recent) = random_node_packet()?;
+        pkarr.publish(&recent).await?;
+        assert_eq!(replicator.pull(&peer_url).await?, 1);
+
+        // A packet with a timestamp a day before the replicated one arrives at the peer later.
+        let day = Duration::from_secs(60 * 60 * 24).as_micros() as u64;
+        let secret_key = iroh_net::key::SecretKey::generate();
+        let old = node_packet_with_timestamp(&secret_key, recent.timestamp() - day)?;
+        pkarr.publish(&old).await?;
+        assert_eq!(replicator.pull(&peer_url).await?, 1);
+        let key = PublicKeyBytes::from_signed_packet(&old);
+        assert!(store.get_signed_packet(&key).await?.is_some());
+
+        peer.shutdown().await?;
+        Ok(())
+    }
+}
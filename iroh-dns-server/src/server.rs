@@ -1,20 +1,28 @@
 //! The main server which combines the DNS and HTTP(S) servers.
 
+use std::{path::Path, sync::Arc};
+
 use anyhow::Result;
 use iroh_metrics::metrics::start_metrics_server;
+use tokio::sync::Semaphore;
 use tracing::info;
 
 use crate::{
     config::Config,
-    dns::{DnsHandler, DnsServer},
+    dns::{DnsHandler, DnsServer, ZoneSigners},
     http::HttpServer,
+    replication::Replicator,
     state::AppState,
     store::ZoneStore,
+    upstream::{MainlineClient, RelayClient, RelayForwarder},
 };
 
 /// Spawn the server and run until the `Ctrl-C` signal is received, then shutdown.
 pub async fn run_with_config_until_ctrl_c(config: Config) -> Result<()> {
-    let store = ZoneStore::persistent(Config::signed_packet_store_path()?)?;
+    let store = ZoneStore::persistent(
+        Config::signed_packet_store_path()?,
+        config.zone_store_options(),
+    )?;
     let server = Server::spawn(config, store).await?;
     tokio::signal::ctrl_c().await?;
     info!("shutdown");
@@ -22,24 +30,114 @@ pub async fn run_with_config_until_ctrl_c(config: Config) -> Result<()> {
     Ok(())
 }
 
+/// Export all signed packets from the store at [`Config::signed_packet_store_path`] to a dump
+/// file.
+///
+/// The store must not be opened by a running server at the same time.
+pub async fn export_store(config: &Config, path: impl AsRef<Path>) -> Result<()> {
+    let store = ZoneStore::persistent(
+        Config::signed_packet_store_path()?,
+        config.zone_store_options(),
+    )?;
+    let count = store.export(path).await?;
+    info!(count, "exported signed packets");
+    Ok(())
+}
+
+/// Import signed packets from a dump file into the store at [`Config::signed_packet_store_path`].
+///
+/// Packets are only stored if their signature verifies and they are newer than the already stored
+/// packet for their pubkey. The store must not be opened by a running server at the same time.
+pub async fn import_store(config: &Config, path: impl AsRef<Path>) -> Result<()> {
+    let store = ZoneStore::persistent(
+        Config::signed_packet_store_path()?,
+        config.zone_store_options(),
+    )?;
+    let (count, updated, invalid) = store.import(path).await?;
+    info!(count, updated, invalid, "imported signed packets");
+    Ok(())
+}
+
+/// Print the DS records to publish in the parent zones of all origins with DNSSEC enabled.
+///
+/// Signing keys that don't exist yet are generated.
+pub fn print_ds_records(config: &Config) -> Result<()> {
+    let origins = config.dns.origin_names()?;
+    let signers = ZoneSigners::load(&origins, config.dns.dnssec.as_ref())?;
+    for signer in signers.iter() {
+        println!("{}", signer.ds_record()?);
+    }
+    Ok(())
+}
+
 /// The iroh-dns server.
 pub struct Server {
     http_server: HttpServer,
     dns_server: DnsServer,
     metrics_task: tokio::task::JoinHandle<anyhow::Result<()>>,
+    gc_task: Option<tokio::task::JoinHandle<()>>,
+    republish_task: Option<tokio::task::JoinHandle<()>>,
+    replication_task: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl Server {
     /// Spawn the server.
     ///
     /// This will spawn several background tasks:
-    /// * A DNS server task
+    /// * A DNS server task, with DNS-over-TLS and DNS-over-QUIC listeners if `config.dns.tls`
+    ///   and `config.dns.quic` are set
     /// * A HTTP server task, if `config.http` is not empty
     /// * A HTTPS server task, if `config.https` is not empty
-    pub async fn spawn(config: Config, store: ZoneStore) -> Result<Self> {
+    /// * A garbage collection task for stale packets, if `config.store.gc_enabled` is set
+    /// * A task republishing stored packets to the mainline DHT, if `config.mainline.republish`
+    ///   is set
+    /// * A task pulling packets from peer instances, if configured in `config.replication`
+    ///
+    /// Pubkeys not found in the store are resolved from the upstream relay in `config.proxy`, if
+    /// set, and then from the mainline DHT, if `config.mainline` is enabled.
+    pub async fn spawn(config: Config, mut store: ZoneStore) -> Result<Self> {
+        if let Some(url) = config.proxy_relay()? {
+            info!(%url, "proxying lookups to upstream pkarr relay");
+            store = store.with_upstream(RelayClient::new(url)?);
+        }
+        let mut republish_task = None;
+        let resolve_from_dht = config.mainline_config().is_some();
+        let republish = config.republish_options();
+        if resolve_from_dht || republish.is_some() {
+            let bootstrap = config.mainline.as_ref().and_then(|c| c.bootstrap.clone());
+            let client = MainlineClient::new(bootstrap)?;
+            if resolve_from_dht {
+                info!("resolving from the mainline DHT");
+                store = store.with_upstream(client.clone());
+            }
+            if let Some(options) = republish {
+                info!("republishing to the mainline DHT");
+                republish_task = Some(store.spawn_republisher(Arc::new(client), options));
+            }
+        }
+        let gc_task = config.gc_options().map(|options| store.spawn_gc(options));
         let dns_handler = DnsHandler::new(store.clone(), &config.dns)?;
 
-        let state = AppState { store, dns_handler };
+        let replication = config.replication_options()?;
+        let replication_token = replication.as_ref().map(|r| Arc::from(r.token.as_str()));
+        let replication_task = match replication {
+            Some(options) if !options.peers.is_empty() => {
+                Some(Replicator::new(store.clone(), options)?.spawn())
+            }
+            _ => None,
+        };
+        let forwarder = config
+            .forward_options()?
+            .map(RelayForwarder::new)
+            .transpose()?;
+
+        let state = AppState {
+            store,
+            dns_handler,
+            forwarder,
+            replication_token,
+            watch_permits: Arc::new(Semaphore::new(config.max_watchers())),
+        };
 
         let metrics_addr = config.metrics_addr();
         let metrics_task = tokio::task::spawn(async move {
@@ -49,17 +147,34 @@ impl Server {
             Ok(())
         });
         let http_server = HttpServer::spawn(config.http, config.https, state.clone()).await?;
-        let dns_server = DnsServer::spawn(config.dns, state.dns_handler.clone()).await?;
+        let dns_server = DnsServer::spawn(
+            config.dns,
+            state.dns_handler.clone(),
+            http_server.tls_material(),
+        )
+        .await?;
         Ok(Self {
             http_server,
             dns_server,
             metrics_task,
+            gc_task,
+            republish_task,
+            replication_task,
         })
     }
 
     /// Cancel the server tasks and wait for all tasks to complete.
     pub async fn shutdown(self) -> Result<()> {
         self.metrics_task.abort();
+        if let Some(gc_task) = self.gc_task {
+            gc_task.abort();
+        }
+        if let Some(republish_task) = self.republish_task {
+            republish_task.abort();
+        }
+        if let Some(replication_task) = self.replication_task {
+            replication_task.abort();
+        }
         let (res1, res2) = tokio::join!(self.dns_server.shutdown(), self.http_server.shutdown(),);
         res1?;
         res2?;
@@ -75,6 +190,15 @@ impl Server {
             res = self.http_server.run_until_done() => res?,
         }
         self.metrics_task.abort();
+        if let Some(gc_task) = self.gc_task {
+            gc_task.abort();
+        }
+        if let Some(republish_task) = self.republish_task {
+            republish_task.abort();
+        }
+        if let Some(replication_task) = self.replication_task {
+            replication_task.abort();
+        }
         Ok(())
     }
 
@@ -86,6 +210,39 @@ impl Server {
     /// HTTP server.
     #[cfg(test)]
     pub async fn spawn_for_tests() -> Result<(Self, std::net::SocketAddr, url::Url)> {
+        Self::spawn_for_tests_with_config(|_config| {}).await
+    }
+
+    /// Spawn a server suitable for testing, with a modified config.
+    ///
+    /// See [`Self::spawn_for_tests`].
+    #[cfg(test)]
+    pub async fn spawn_for_tests_with_config(
+        f: impl FnOnce(&mut Config),
+    ) -> Result<(Self, std::net::SocketAddr, url::Url)> {
+        use crate::store::SignedPacketStore;
+
+        let config = Self::test_config(f);
+        let options = config.zone_store_options();
+        let packet_store =
+            SignedPacketStore::in_memory()?.with_history_size(options.history_size)?;
+        let store = ZoneStore::with_options(packet_store, options)?;
+        Self::spawn_test_server(config, store).await
+    }
+
+    /// Spawn a server suitable for testing on top of an existing store, with a modified config.
+    ///
+    /// See [`Self::spawn_for_tests`].
+    #[cfg(test)]
+    pub async fn spawn_for_tests_with_store(
+        store: ZoneStore,
+        f: impl FnOnce(&mut Config),
+    ) -> Result<(Self, std::net::SocketAddr, url::Url)> {
+        Self::spawn_test_server(Self::test_config(f), store).await
+    }
+
+    #[cfg(test)]
+    fn test_config(f: impl FnOnce(&mut Config)) -> Config {
         use crate::config::MetricsConfig;
         use std::net::{IpAddr, Ipv4Addr};
 
@@ -96,8 +253,15 @@ impl Server {
         config.http.as_mut().unwrap().bind_addr = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
         config.https = None;
         config.metrics = Some(MetricsConfig::disabled());
+        f(&mut config);
+        config
+    }
 
-        let store = ZoneStore::in_memory()?;
+    #[cfg(test)]
+    async fn spawn_test_server(
+        config: Config,
+        store: ZoneStore,
+    ) -> Result<(Self, std::net::SocketAddr, url::Url)> {
         let server = Self::spawn(config, store).await?;
         let dns_addr = server.dns_server.local_addr();
         let http_addr = server.http_server.http_addr().expect("http is set");
@@ -1,6 +1,10 @@
 //! Shared state and store for the iroh-dns-server
 
-use crate::{dns::DnsHandler, store::ZoneStore};
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+use crate::{dns::DnsHandler, store::ZoneStore, upstream::RelayForwarder};
 
 /// The shared app state.
 #[derive(Clone)]
@@ -9,4 +13,10 @@ pub struct AppState {
     pub store: ZoneStore,
     /// Handler for DNS requests
     pub dns_handler: DnsHandler,
+    /// Forwards publishes to other pkarr relays, if configured
+    pub forwarder: Option<RelayForwarder>,
+    /// Token that peers present to pull packets, if replication is enabled
+    pub replication_token: Option<Arc<str>>,
+    /// Permits for concurrently open watch streams
+    pub watch_permits: Arc<Semaphore>,
 }
@@ -1,124 +1,1249 @@
 //! Pkarr packet store used to resolve DNS queries.
 
-use std::{collections::BTreeMap, num::NonZeroUsize, path::Path, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    num::NonZeroUsize,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use anyhow::Result;
-use hickory_proto::rr::{Name, RecordSet, RecordType, RrKey};
-use iroh_metrics::inc;
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt, StreamExt, TryStreamExt,
+};
+use hickory_proto::rr::{LowerName, Name, RecordSet, RecordType, RrKey};
+use iroh_metrics::{inc, inc_by};
 use lru::LruCache;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, MutexGuard};
 use pkarr::SignedPacket;
+use tokio::{
+    sync::{broadcast, watch, Semaphore},
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+use ttl_cache::TtlCache;
 
 use crate::{
+    dns::ZoneSigner,
     metrics::Metrics,
-    util::{signed_packet_to_hickory_records_without_origin, PublicKeyBytes},
+    upstream::{UpstreamPublisher, UpstreamResolver},
+    util::{encoded_packet_to_hickory_records_without_origin, record_set_append_origin},
 };
 
-use self::signed_packets::SignedPacketStore;
+pub use crate::util::PublicKeyBytes;
 
+pub use self::{
+    memory::InMemoryPacketStore,
+    signed_packets::{SignedPacketStore, DEFAULT_HISTORY_SIZE},
+};
+
+use self::writer::StoreWriter;
+
+mod dump;
+mod memory;
 mod signed_packets;
+mod writer;
 
 /// Cache up to 1 million pkarr zones by default
 pub const DEFAULT_CACHE_CAPACITY: usize = 1024 * 1024;
 
+/// Split the zone cache into 64 shards by default
+pub const DEFAULT_CACHE_SHARDS: usize = 64;
+
+/// Remember up to 65536 pubkeys without a stored packet, divided between the cache shards
+pub const NEGATIVE_CACHE_CAPACITY: usize = 64 * 1024;
+
+/// Remember pubkeys without a stored packet for 30 seconds
+pub const NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Run at most 64 upstream lookups at once, further lookups fail until one completes
+pub const MAX_CONCURRENT_UPSTREAM_LOOKUPS: usize = 64;
+
+/// Retry resolving a packet from upstream again after 30 seconds if its revalidation failed
+pub const REVALIDATE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Buffer up to 1024 updates for each subscriber of the change feed
+pub const CHANGE_FEED_CAPACITY: usize = 1024;
+
+/// Commit up to 1024 upserts in a single write transaction by default
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 1024;
+
+/// Don't wait for more upserts to join a batch by default
+pub const DEFAULT_MAX_BATCH_TIME: Duration = Duration::ZERO;
+
+/// Options for a [`ZoneStore`].
+#[derive(Debug, Clone)]
+pub struct ZoneStoreOptions {
+    /// Maximum number of upserts that are committed in a single write transaction.
+    pub max_batch_size: usize,
+    /// Maximum time to wait for more upserts to join a batch before committing it.
+    ///
+    /// This is the latency added to each publish when there are no concurrent publishes. With a
+    /// zero batch time, batches only form from upserts that queue up while the previous batch is
+    /// being committed.
+    pub max_batch_time: Duration,
+    /// Number of packets to keep in the history of each public key.
+    ///
+    /// Only applies to stores created with [`ZoneStore::persistent`].
+    pub history_size: usize,
+    /// Maximum number of zones in the in-memory cache.
+    pub cache_capacity: usize,
+    /// Maximum approximate memory usage of the in-memory cache in bytes, if set.
+    ///
+    /// The size of a cached zone is estimated from the size of its signed packet.
+    pub cache_max_bytes: Option<usize>,
+    /// Number of shards of the in-memory cache.
+    ///
+    /// Each shard has its own lock, so that concurrent lookups of different pubkeys rarely
+    /// contend. The capacity, the memory limit and the capacity of the cache of pubkeys without
+    /// a packet are divided evenly between the shards.
+    pub cache_shards: usize,
+}
+
+impl Default for ZoneStoreOptions {
+    fn default() -> Self {
+        Self {
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            max_batch_time: DEFAULT_MAX_BATCH_TIME,
+            history_size: DEFAULT_HISTORY_SIZE,
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            cache_max_bytes: None,
+            cache_shards: DEFAULT_CACHE_SHARDS,
+        }
+    }
+}
+
+/// Evict packets older than one week by default
+pub const DEFAULT_GC_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// Run the garbage collection once per hour by default
+pub const DEFAULT_GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Remove at most 1000 packets per write transaction during garbage collection
+///
+/// Publishes are committed by the same writer, so they only wait for one chunk.
+const GC_CHUNK_SIZE: usize = 1000;
+
+/// Options for the garbage collection of stale signed packets.
+#[derive(Debug, Clone, Copy)]
+pub struct GcOptions {
+    /// Packets whose pkarr timestamp is older than this are evicted.
+    pub max_age: Duration,
+    /// Interval between garbage collection runs.
+    pub interval: Duration,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            max_age: DEFAULT_GC_MAX_AGE,
+            interval: DEFAULT_GC_INTERVAL,
+        }
+    }
+}
+
+/// Republish packets to the DHT once per hour by default
+pub const DEFAULT_REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Publish up to 16 packets concurrently when republishing
+const REPUBLISH_CONCURRENCY: usize = 16;
+
+/// Options for republishing stored packets upstream.
+#[derive(Debug, Clone, Copy)]
+pub struct RepublishOptions {
+    /// Only packets whose pkarr timestamp is at most this old are republished.
+    pub max_age: Duration,
+    /// Interval between republishing runs.
+    pub interval: Duration,
+}
+
+impl Default for RepublishOptions {
+    fn default() -> Self {
+        Self {
+            max_age: DEFAULT_GC_MAX_AGE,
+            interval: DEFAULT_REPUBLISH_INTERVAL,
+        }
+    }
+}
+
+/// Iterator over stored signed packets, see [`PacketStorage::iter`].
+pub type PacketIter<'a> = Box<dyn Iterator<Item = Result<SignedPacket>> + 'a>;
+
+/// Storage backend for pkarr signed packets.
+///
+/// Implementations keep only the newest packet for each public key. The [`ZoneStore`] puts an
+/// in-memory cache in front of the storage.
+///
+/// [`SignedPacketStore`] stores packets in a [redb](https://docs.rs/redb) database,
+/// [`InMemoryPacketStore`] keeps them in memory only.
+pub trait PacketStorage: std::fmt::Debug + Send + Sync + 'static {
+    /// Get the stored packet for a public key.
+    fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>>;
+
+    /// Store a packet, unless a more recent or the same packet for its public key is already
+    /// stored.
+    ///
+    /// If `unmodified_since` is set, the packet is only stored if the pkarr timestamp of the
+    /// stored packet is not after `unmodified_since`. The check and the write must be atomic, see
+    /// [`UpsertOutcome::check`].
+    ///
+    /// Storing a packet drops the [`ZoneUpdate`] of its public key, if any.
+    fn upsert(&self, packet: SignedPacket, unmodified_since: Option<u64>) -> Result<UpsertOutcome>;
+
+    /// Store several packets at once, see [`Self::upsert`].
+    ///
+    /// Returns the outcome for each packet. Implementations should apply all packets in a
+    /// single transaction. The default implementation calls [`Self::upsert`] for each packet.
+    fn upsert_batch(
+        &self,
+        packets: Vec<(SignedPacket, Option<u64>)>,
+    ) -> Result<Vec<UpsertOutcome>> {
+        packets
+            .into_iter()
+            .map(|(packet, unmodified_since)| self.upsert(packet, unmodified_since))
+            .collect()
+    }
+
+    /// Remove the packet and the [`ZoneUpdate`] for a public key.
+    ///
+    /// Returns whether a packet was removed.
+    fn remove(&self, key: &PublicKeyBytes) -> Result<bool>;
+
+    /// Iterate over all stored packets.
+    fn iter(&self) -> Result<PacketIter<'_>>;
+
+    /// Get the most recent packets for a public key, newest first.
+    ///
+    /// The default implementation only returns the current packet.
+    fn history(&self, key: &PublicKeyBytes) -> Result<Vec<SignedPacket>> {
+        Ok(self.get(key)?.into_iter().collect())
+    }
+
+    /// Remove up to `limit` packets whose pkarr timestamp is older than `cutoff`, and zone
+    /// updates applied before `cutoff`.
+    ///
+    /// `cutoff` is a pkarr timestamp, i.e. microseconds since the unix epoch.
+    /// Returns the public keys of the removed packets and zone updates, at most `limit`. Fewer
+    /// than `limit` keys are only returned once nothing older than `cutoff` is left.
+    ///
+    /// The default implementation scans all packets with [`Self::iter`] and removes them with
+    /// [`Self::remove`], so it only removes the zone updates of expired packets.
+    fn remove_older_than(&self, cutoff: u64, limit: usize) -> Result<Vec<PublicKeyBytes>> {
+        let mut expired = vec![];
+        for packet in self.iter()? {
+            let packet = packet?;
+            if *packet.timestamp() < cutoff {
+                expired.push(PublicKeyBytes::from_signed_packet(&packet));
+                if expired.len() == limit {
+                    break;
+                }
+            }
+        }
+        let mut removed = Vec::with_capacity(expired.len());
+        for key in expired {
+            if self.remove(&key)? {
+                removed.push(key);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// List the timestamps and public keys of packets updated at or after `since`, ordered by
+    /// timestamp and public key.
+    ///
+    /// If `after` is set, only entries after `(since, after)` are listed, so that the last
+    /// returned entry can be used to continue the listing. At most `limit` entries are returned.
+    ///
+    /// The default implementation scans all packets with [`Self::iter`].
+    fn updated_since(
+        &self,
+        since: u64,
+        after: Option<&PublicKeyBytes>,
+        limit: usize,
+    ) -> Result<Vec<(u64, PublicKeyBytes)>> {
+        let mut out = vec![];
+        for packet in self.iter()? {
+            let packet = packet?;
+            let entry = (
+                *packet.timestamp(),
+                PublicKeyBytes::from_signed_packet(&packet),
+            );
+            let include = match after {
+                Some(after) => (entry.0, &entry.1) > (since, after),
+                None => entry.0 >= since,
+            };
+            if include {
+                out.push(entry);
+            }
+        }
+        out.sort();
+        out.truncate(limit);
+        Ok(out)
+    }
+
+    /// Get the zone update stored on top of the packet for a public key, see [`ZoneUpdate`].
+    ///
+    /// The returned update might not apply to the stored packet, see [`ZoneUpdate::applies_to`].
+    fn get_zone_update(&self, key: &PublicKeyBytes) -> Result<Option<ZoneUpdate>>;
+
+    /// Store a zone update, replacing the previous one for its public key.
+    ///
+    /// The update is only stored if its [`ZoneUpdate::base_timestamp`] matches the stored packet,
+    /// and if the timestamp of the previous update that applies to the stored packet is
+    /// `previous`, or if there is none and `previous` is `None`. Otherwise
+    /// [`UpsertOutcome::PreconditionFailed`] is returned. The checks and the write must be atomic.
+    fn put_zone_update(&self, update: ZoneUpdate, previous: Option<u64>) -> Result<UpsertOutcome>;
+
+    /// List the sequence numbers and public keys of packets and zone updates stored after the
+    /// sequence number `after`, ordered by sequence number. At most `limit` entries are returned.
+    ///
+    /// Each stored packet or zone update is assigned the next sequence number of this storage,
+    /// starting at 1, and the previous sequence number of its public key is dropped. Unlike pkarr timestamps, which
+    /// are chosen by the publishers, sequence numbers follow the order in which packets were
+    /// stored, so that the last returned sequence number can be used to continue the listing
+    /// without missing packets that are stored later.
+    fn updated_after(&self, after: u64, limit: usize) -> Result<Vec<(u64, PublicKeyBytes)>>;
+
+    /// Get a metadata value, e.g. a replication cursor.
+    fn metadata(&self, key: &str) -> Result<Option<u64>>;
+
+    /// Set a metadata value.
+    fn set_metadata(&self, key: &str, value: u64) -> Result<()>;
+}
+
+/// The outcome of storing a packet, see [`PacketStorage::upsert`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpsertOutcome {
+    /// The packet was stored as the newest packet for its public key.
+    Stored,
+    /// The same packet is already stored.
+    Unchanged,
+    /// A more recent packet is already stored.
+    Outdated,
+    /// The stored packet was modified after the `unmodified_since` precondition.
+    PreconditionFailed,
+}
+
+impl UpsertOutcome {
+    /// Check whether `packet` may replace the `existing` packet for its public key.
+    ///
+    /// Returns `None` if it may, and the outcome otherwise.
+    pub fn check(
+        existing: &SignedPacket,
+        packet: &SignedPacket,
+        unmodified_since: Option<u64>,
+    ) -> Option<Self> {
+        if existing.as_bytes() == packet.as_bytes() {
+            Some(Self::Unchanged)
+        } else if unmodified_since.is_some_and(|since| *existing.timestamp() > since) {
+            Some(Self::PreconditionFailed)
+        } else if existing.more_recent_than(packet) {
+            Some(Self::Outdated)
+        } else {
+            None
+        }
+    }
+}
+
 /// Where a new pkarr packet comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PacketSource {
     /// Received via HTTPS relay PUT
     PkarrPublish,
+    /// Imported from a dump file
+    Import,
+    /// Resolved from an [`UpstreamResolver`] after a local miss
+    Upstream,
+    /// Resolved from the upstream pkarr relay in proxy mode after a local miss
+    RelayProxy,
+    /// Pulled from a peer instance
+    Replication,
+    /// Changed with a DNS UPDATE, see [`ZoneUpdate`]
+    DnsUpdate,
+}
+
+/// Zones changed with a DNS UPDATE are limited to the 1000 bytes of a pkarr packet.
+pub const MAX_ZONE_UPDATE_SIZE: usize = 1000;
+
+/// A zone changed with a DNS UPDATE signed with SIG(0) by the zone's key.
+///
+/// The server can't create a pkarr signature for the changed zone, so it is stored apart from the
+/// signed packets, on top of the signed packet it was based on. It is served over DNS and
+/// replicated to peer instances, but not served to relay clients or upstreams. It is dropped once
+/// a newer signed packet is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneUpdate {
+    /// The pubkey of the zone.
+    pub pubkey: PublicKeyBytes,
+    /// The pkarr timestamp of the signed packet the update was applied to, or 0 if no packet was
+    /// stored.
+    pub base_timestamp: u64,
+    /// When the update was applied, in microseconds since the unix epoch.
+    pub timestamp: u64,
+    /// The encoded DNS packet with all records of the zone.
+    pub encoded_packet: Bytes,
+}
+
+impl ZoneUpdate {
+    /// Whether the update applies on top of `packet`, the stored packet for its pubkey.
+    pub fn applies_to(&self, packet: Option<&SignedPacket>) -> bool {
+        self.base_timestamp == packet.map_or(0, |packet| *packet.timestamp())
+    }
+}
+
+/// An update of the stored packet or zone update for a pubkey, see [`ZoneStore::subscribe`].
+#[derive(Debug, Clone)]
+pub struct PacketUpdate {
+    /// The pubkey whose packet was updated.
+    pub pubkey: PublicKeyBytes,
+    /// The pkarr timestamp of the new packet, or the [`ZoneUpdate::timestamp`] for
+    /// [`PacketSource::DnsUpdate`], in microseconds since the unix epoch.
+    pub timestamp: u64,
+    /// Where the new packet comes from.
+    pub source: PacketSource,
+}
+
+/// Watches the stored packet of a single pubkey, see [`ZoneStore::watch`].
+#[derive(Debug)]
+pub struct PacketWatcher {
+    pubkey: PublicKeyBytes,
+    receiver: watch::Receiver<u64>,
+    watchers: Arc<Mutex<HashMap<PublicKeyBytes, watch::Sender<u64>>>>,
+}
+
+impl PacketWatcher {
+    /// Wait until a new packet or zone update is stored for the pubkey.
+    ///
+    /// Returns the timestamp of the newest stored packet or zone update, see
+    /// [`PacketUpdate::timestamp`]. Updates that are stored while
+    /// the watcher isn't waiting are coalesced into one.
+    pub async fn changed(&mut self) -> Option<u64> {
+        self.receiver.changed().await.ok()?;
+        Some(*self.receiver.borrow_and_update())
+    }
+}
+
+impl Drop for PacketWatcher {
+    fn drop(&mut self) {
+        // New watchers subscribe under the same lock, so the count can't increase meanwhile.
+        let mut watchers = self.watchers.lock();
+        if let Some(sender) = watchers.get(&self.pubkey) {
+            if sender.receiver_count() <= 1 {
+                watchers.remove(&self.pubkey);
+            }
+        }
+    }
 }
 
 /// A store for pkarr signed packets.
 ///
-/// Packets are stored in the persistent [`SignedPacketStore`], and cached on-demand in an in-memory LRU
-/// cache used for resolving DNS queries.
+/// Packets are stored in a [`PacketStorage`], usually the persistent [`SignedPacketStore`], and
+/// cached on-demand in an in-memory LRU cache used for resolving DNS queries.
+///
+/// Storage access never blocks the async runtime: reads run on the blocking thread pool, and
+/// writes are sent to a dedicated writer thread.
+///
+/// If [`UpstreamResolver`]s are set, pubkeys that are not found in the storage are resolved
+/// from upstream, and the results are stored. Packets resolved from upstream are resolved again
+/// in the background when they are looked up after their minimum TTL passed, so that updates
+/// published upstream are picked up.
 #[derive(Debug, Clone)]
 pub struct ZoneStore {
-    cache: Arc<Mutex<ZoneCache>>,
-    store: Arc<SignedPacketStore>,
+    cache: Arc<ShardedZoneCache>,
+    store: Arc<dyn PacketStorage>,
+    writer: StoreWriter,
+    upstreams: Arc<[Arc<dyn UpstreamResolver>]>,
+    /// Running upstream lookups, shared by all concurrent lookups of the same pubkey.
+    upstream_lookups: Arc<Mutex<HashMap<PublicKeyBytes, UpstreamLookup>>>,
+    upstream_permits: Arc<Semaphore>,
+    changes: broadcast::Sender<PacketUpdate>,
+    /// The timestamps of the newest stored packets, for each pubkey with watchers.
+    watchers: Arc<Mutex<HashMap<PublicKeyBytes, watch::Sender<u64>>>>,
 }
 
+/// The result of an upstream lookup, see [`ZoneStore::resolve_upstream`].
+type UpstreamLookup = Shared<BoxFuture<'static, Result<bool, Arc<anyhow::Error>>>>;
+
 impl ZoneStore {
     /// Create a persistent store
-    pub fn persistent(path: impl AsRef<Path>) -> Result<Self> {
-        let packet_store = SignedPacketStore::persistent(path)?;
-        Ok(Self::new(packet_store))
+    pub fn persistent(path: impl AsRef<Path>, options: ZoneStoreOptions) -> Result<Self> {
+        let packet_store =
+            SignedPacketStore::persistent(path)?.with_history_size(options.history_size)?;
+        Self::with_options(packet_store, options)
     }
 
     /// Create an in-memory store.
+    ///
+    /// This uses a [`SignedPacketStore`] with an in-memory backend. Use [`Self::new`] with an
+    /// [`InMemoryPacketStore`] for a lighter store without the timestamp index and history.
     pub fn in_memory() -> Result<Self> {
         let packet_store = SignedPacketStore::in_memory()?;
-        Ok(Self::new(packet_store))
+        Self::new(packet_store)
     }
 
-    /// Create a new zone store.
-    pub fn new(store: SignedPacketStore) -> Self {
-        let zone_cache = ZoneCache::new(DEFAULT_CACHE_CAPACITY);
-        Self {
-            store: Arc::new(store),
-            cache: Arc::new(Mutex::new(zone_cache)),
-        }
+    /// Create a new zone store on top of a [`PacketStorage`] with default options.
+    ///
+    /// This spawns the writer thread for the storage.
+    pub fn new(store: impl PacketStorage) -> Result<Self> {
+        Self::with_options(store, Default::default())
+    }
+
+    /// Create a new zone store on top of a [`PacketStorage`].
+    ///
+    /// This spawns the writer thread for the storage.
+    pub fn with_options(store: impl PacketStorage, options: ZoneStoreOptions) -> Result<Self> {
+        let store: Arc<dyn PacketStorage> = Arc::new(store);
+        let writer = StoreWriter::spawn(store.clone(), &options)?;
+        let zone_cache = ShardedZoneCache::new(
+            options.cache_shards,
+            options.cache_capacity,
+            options.cache_max_bytes,
+        );
+        Ok(Self {
+            store,
+            writer,
+            cache: Arc::new(zone_cache),
+            upstreams: Vec::new().into(),
+            upstream_lookups: Default::default(),
+            upstream_permits: Arc::new(Semaphore::new(MAX_CONCURRENT_UPSTREAM_LOOKUPS)),
+            changes: broadcast::channel(CHANGE_FEED_CAPACITY).0,
+            watchers: Default::default(),
+        })
+    }
+
+    /// Resolve pubkeys that are not found in the storage from an [`UpstreamResolver`].
+    ///
+    /// Upstreams are consulted in the order they were added, until one of them has a packet.
+    pub fn with_upstream(mut self, upstream: impl UpstreamResolver) -> Self {
+        let mut upstreams = self.upstreams.to_vec();
+        upstreams.push(Arc::new(upstream));
+        self.upstreams = upstreams.into();
+        self
     }
 
     /// Resolve a DNS query.
-    // allow unused async: this will be async soon.
-    #[allow(clippy::unused_async)]
     pub async fn resolve(
         &self,
         pubkey: &PublicKeyBytes,
         name: &Name,
         record_type: RecordType,
     ) -> Result<Option<Arc<RecordSet>>> {
-        if let Some(rset) = self.cache.lock().resolve(pubkey, name, record_type) {
-            return Ok(Some(rset));
+        self.revalidate(pubkey);
+        let generation = {
+            let mut cache = self.cache.shard(pubkey);
+            if let Some(rset) = cache.resolve(pubkey, name, record_type) {
+                inc!(Metrics, store_cache_hits);
+                return Ok(Some(rset));
+            }
+            if cache.is_not_found(pubkey) {
+                inc!(Metrics, store_cache_negative_hits);
+                return Ok(None);
+            }
+            cache.generation()
+        };
+        inc!(Metrics, store_cache_misses);
+
+        match self.fetch_zone(pubkey).await? {
+            Some(zone) => self.cache.shard(pubkey).insert_and_resolve(
+                pubkey,
+                zone,
+                name,
+                record_type,
+                generation,
+            ),
+            None => {
+                self.cache
+                    .shard(pubkey)
+                    .insert_not_found(pubkey, generation);
+                Ok(None)
+            }
         }
+    }
+
+    /// Resolve a DNS query for a pkarr zone below the origin of `signer`, with a DNSSEC signature.
+    ///
+    /// Unlike [`Self::resolve`], the returned record set has the full name including the pubkey
+    /// and the origin. Signatures are cached along with the zone, and are refreshed once half of
+    /// their validity has passed.
+    pub async fn resolve_signed(
+        &self,
+        pubkey: &PublicKeyBytes,
+        name: &Name,
+        record_type: RecordType,
+        signer: &ZoneSigner,
+        serial: u32,
+    ) -> Result<Option<Arc<RecordSet>>> {
+        let key = (
+            LowerName::from(signer.origin()),
+            RrKey::new(name.into(), record_type),
+        );
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        self.revalidate(pubkey);
+        let generation = {
+            let mut cache = self.cache.shard(pubkey);
+            if let Some(rset) = cache.resolve_signed(pubkey, &key, now) {
+                return Ok(Some(rset));
+            }
+            cache.generation()
+        };
+        let Some(rset) = self.resolve(pubkey, name, record_type).await? else {
+            return Ok(None);
+        };
+        let zone_origin = Name::parse(&pubkey.to_z32(), Some(signer.origin()))?;
+        let mut rset = record_set_append_origin(&rset, &zone_origin, serial)?;
+        let refresh_at = signer.sign(&mut rset)?;
+        let rset = Arc::new(rset);
+        self.cache
+            .shard(pubkey)
+            .insert_signed(pubkey, key, rset.clone(), refresh_at, generation);
+        Ok(Some(rset))
+    }
 
-        if let Some(packet) = self.store.get(pubkey)? {
-            return self
+    /// Get the record types at a name in the zone of a pubkey.
+    ///
+    /// Returns `None` if the name does not exist. A name without records of its own exists if
+    /// there are records below it.
+    pub async fn record_types(
+        &self,
+        pubkey: &PublicKeyBytes,
+        name: &Name,
+    ) -> Result<Option<Vec<RecordType>>> {
+        self.revalidate(pubkey);
+        let generation = {
+            let mut cache = self.cache.shard(pubkey);
+            if let Some(types) = cache.record_types(pubkey, name) {
+                return Ok(types);
+            }
+            if cache.is_not_found(pubkey) {
+                return Ok(None);
+            }
+            cache.generation()
+        };
+        match self.fetch_zone(pubkey).await? {
+            Some(zone) => self
                 .cache
-                .lock()
-                .insert_and_resolve(&packet, name, record_type);
+                .shard(pubkey)
+                .insert_and_get_record_types(pubkey, zone, name, generation),
+            None => {
+                self.cache
+                    .shard(pubkey)
+                    .insert_not_found(pubkey, generation);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Get the latest signed packet for a pubkey, resolving it from upstream if it is not found
+    /// in the storage.
+    ///
+    /// Unlike [`Self::get_signed_packet`], this uses the cache of pubkeys without a packet. Zone
+    /// updates are not taken into account, see [`ZoneUpdate`].
+    pub async fn resolve_signed_packet(
+        &self,
+        pubkey: &PublicKeyBytes,
+    ) -> Result<Option<SignedPacket>> {
+        self.revalidate(pubkey);
+        let generation = {
+            let cache = self.cache.shard(pubkey);
+            if cache.is_not_found(pubkey) {
+                inc!(Metrics, store_cache_negative_hits);
+                return Ok(None);
+            }
+            cache.generation()
         };
+        let packet = self.fetch_signed_packet(pubkey).await?;
+        if packet.is_none() {
+            self.cache
+                .shard(pubkey)
+                .insert_not_found(pubkey, generation);
+        }
+        Ok(packet)
+    }
 
-        // This would be where mainline discovery could be added.
+    /// Get the zone of a pubkey to serve over DNS: the zone update if one applies to the stored
+    /// packet, and the signed packet otherwise.
+    async fn fetch_zone(&self, pubkey: &PublicKeyBytes) -> Result<Option<CachedZone>> {
+        let packet = self.fetch_signed_packet(pubkey).await?;
+        let update = self.get_zone_update(pubkey).await?;
+        match (update, packet) {
+            (Some(update), packet) if update.applies_to(packet.as_ref()) => {
+                CachedZone::from_zone_update(&update).map(Some)
+            }
+            (_, Some(packet)) => CachedZone::from_signed_packet(&packet).map(Some),
+            (_, None) => Ok(None),
+        }
+    }
 
-        Ok(None)
+    /// Get the latest signed packet for a pubkey from the storage or else from upstream.
+    async fn fetch_signed_packet(&self, pubkey: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        if let Some(packet) = self.get_signed_packet(pubkey).await? {
+            return Ok(Some(packet));
+        }
+        if !self.resolve_upstream(pubkey).await? {
+            return Ok(None);
+        }
+        // Read the packet back from the store, which might hold an even newer packet by now.
+        self.get_signed_packet(pubkey).await
+    }
+
+    /// Resolve a pubkey from the upstream resolvers, if any, and store the result.
+    ///
+    /// Returns whether a packet was found upstream. Upstream failures are logged and treated as
+    /// not found.
+    ///
+    /// Concurrent lookups of the same pubkey share a single upstream lookup. At most
+    /// [`MAX_CONCURRENT_UPSTREAM_LOOKUPS`] pubkeys are looked up at once, lookups of further
+    /// pubkeys fail.
+    async fn resolve_upstream(&self, pubkey: &PublicKeyBytes) -> Result<bool> {
+        if self.upstreams.is_empty() {
+            return Ok(false);
+        }
+        let lookup = {
+            let mut lookups = self.upstream_lookups.lock();
+            match lookups.get(pubkey) {
+                Some(lookup) => lookup.clone(),
+                None => {
+                    let Ok(permit) = self.upstream_permits.clone().try_acquire_owned() else {
+                        inc!(Metrics, upstream_resolve_error);
+                        bail!("too many concurrent upstream lookups");
+                    };
+                    // The lookup runs in its own task, so that it completes and is removed even
+                    // if all callers are dropped. It waits for the lock to remove itself.
+                    let this = self.clone();
+                    let key = pubkey.clone();
+                    let task = tokio::task::spawn(async move {
+                        let res = this.resolve_from_upstreams(&key).await;
+                        this.upstream_lookups.lock().remove(&key);
+                        drop(permit);
+                        res.map_err(Arc::new)
+                    });
+                    let lookup = async move { task.await.map_err(|err| Arc::new(err.into()))? }
+                        .boxed()
+                        .shared();
+                    lookups.insert(pubkey.clone(), lookup.clone());
+                    lookup
+                }
+            }
+        };
+        lookup.await.map_err(|err| anyhow!("{err:#}"))
+    }
+
+    /// Resolve a pubkey from upstream again in the background, if it was resolved from upstream
+    /// before and its revalidation deadline passed.
+    ///
+    /// The stored packet keeps being served until a newer packet is found.
+    fn revalidate(&self, pubkey: &PublicKeyBytes) {
+        if self.upstreams.is_empty() {
+            return;
+        }
+        if !self
+            .cache
+            .shard(pubkey)
+            .take_revalidation(pubkey, Instant::now())
+        {
+            return;
+        }
+        inc!(Metrics, upstream_revalidations);
+        let this = self.clone();
+        let pubkey = pubkey.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = this.resolve_upstream(&pubkey).await {
+                debug!(%pubkey, ?err, "upstream revalidation failed");
+            }
+        });
+    }
+
+    /// Resolve a pubkey from each upstream resolver in turn, see [`Self::resolve_upstream`].
+    async fn resolve_from_upstreams(&self, pubkey: &PublicKeyBytes) -> Result<bool> {
+        for upstream in self.upstreams.iter() {
+            let packet = match upstream.resolve(pubkey).await {
+                Ok(Some(packet)) => packet,
+                Ok(None) => {
+                    inc!(Metrics, upstream_resolve_notfound);
+                    continue;
+                }
+                Err(err) => {
+                    inc!(Metrics, upstream_resolve_error);
+                    debug!(%pubkey, ?upstream, ?err, "upstream resolve failed");
+                    continue;
+                }
+            };
+            if PublicKeyBytes::from_signed_packet(&packet) != *pubkey {
+                inc!(Metrics, upstream_resolve_error);
+                warn!(%pubkey, ?upstream, "upstream returned a packet for a different pubkey");
+                continue;
+            }
+            inc!(Metrics, upstream_resolve_success);
+            let revalidate_at = revalidation_deadline(&packet);
+            // Stored under the usual rules, i.e. only if newer than the stored packet.
+            self.insert(packet, upstream.source()).await?;
+            self.cache
+                .shard(pubkey)
+                .set_revalidate_at(pubkey, revalidate_at);
+            return Ok(true);
+        }
+        Ok(false)
     }
 
     /// Get the latest signed packet for a pubkey.
-    // allow unused async: this will be async soon.
-    #[allow(clippy::unused_async)]
     pub async fn get_signed_packet(&self, pubkey: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
-        self.store.get(pubkey)
+        let store = self.store.clone();
+        let pubkey = pubkey.clone();
+        tokio::task::spawn_blocking(move || store.get(&pubkey)).await?
+    }
+
+    /// Get the zone update stored for a pubkey, see [`PacketStorage::get_zone_update`].
+    pub async fn get_zone_update(&self, pubkey: &PublicKeyBytes) -> Result<Option<ZoneUpdate>> {
+        let store = self.store.clone();
+        let pubkey = pubkey.clone();
+        tokio::task::spawn_blocking(move || store.get_zone_update(&pubkey)).await?
+    }
+
+    /// Store a zone update on top of the signed packet of its pubkey, see
+    /// [`PacketStorage::put_zone_update`].
+    ///
+    /// Stored updates are sent to the subscribers of [`Self::subscribe`] with `source`, which is
+    /// [`PacketSource::DnsUpdate`] for updates applied by this instance, and wake the watchers of
+    /// the pubkey.
+    pub async fn insert_zone_update(
+        &self,
+        update: ZoneUpdate,
+        previous: Option<u64>,
+        source: PacketSource,
+    ) -> Result<UpsertOutcome> {
+        let pubkey = update.pubkey.clone();
+        let timestamp = update.timestamp;
+        let outcome = self.writer.put_zone_update(update, previous).await?;
+        if outcome == UpsertOutcome::Stored {
+            self.cache.shard(&pubkey).remove(&pubkey);
+            self.notify(pubkey, timestamp, source);
+        }
+        Ok(outcome)
+    }
+
+    /// Get the most recent signed packets for a pubkey, newest first.
+    pub async fn history(&self, pubkey: &PublicKeyBytes) -> Result<Vec<SignedPacket>> {
+        let store = self.store.clone();
+        let pubkey = pubkey.clone();
+        tokio::task::spawn_blocking(move || store.history(&pubkey)).await?
+    }
+
+    /// Subscribe to updates of stored packets.
+    ///
+    /// An update is sent whenever [`Self::insert`] stores a packet or [`Self::insert_zone_update`]
+    /// stores a zone update. Subscribers that fall behind
+    /// by more than [`CHANGE_FEED_CAPACITY`] updates miss the oldest updates, see
+    /// [`broadcast::Receiver::recv`].
+    pub fn subscribe(&self) -> broadcast::Receiver<PacketUpdate> {
+        self.changes.subscribe()
+    }
+
+    /// Watch the stored packet of a single pubkey.
+    ///
+    /// Unlike [`Self::subscribe`], the watcher is only woken for updates of `pubkey`.
+    pub fn watch(&self, pubkey: &PublicKeyBytes) -> PacketWatcher {
+        let mut watchers = self.watchers.lock();
+        let receiver = match watchers.get(pubkey) {
+            Some(sender) => sender.subscribe(),
+            None => {
+                let (sender, receiver) = watch::channel(0);
+                watchers.insert(pubkey.clone(), sender);
+                receiver
+            }
+        };
+        PacketWatcher {
+            pubkey: pubkey.clone(),
+            receiver,
+            watchers: self.watchers.clone(),
+        }
     }
 
     /// Insert a signed packet into the cache and the store.
     ///
     /// Returns whether this produced an update, i.e. whether the packet is the newest for its
     /// pubkey.
-    // allow unused async: this will be async soon.
-    #[allow(clippy::unused_async)]
-    pub async fn insert(&self, signed_packet: SignedPacket, _source: PacketSource) -> Result<bool> {
+    pub async fn insert(&self, signed_packet: SignedPacket, source: PacketSource) -> Result<bool> {
+        let outcome = self.insert_conditional(signed_packet, source, None).await?;
+        Ok(outcome == UpsertOutcome::Stored)
+    }
+
+    /// Insert a signed packet, unless the stored packet for its pubkey was modified after
+    /// `unmodified_since`.
+    ///
+    /// `unmodified_since` is a pkarr timestamp, i.e. microseconds since the unix epoch. The
+    /// precondition is checked in the same write transaction that stores the packet.
+    pub async fn insert_conditional(
+        &self,
+        signed_packet: SignedPacket,
+        source: PacketSource,
+        unmodified_since: Option<u64>,
+    ) -> Result<UpsertOutcome> {
         let pubkey = PublicKeyBytes::from_signed_packet(&signed_packet);
-        if self.store.upsert(signed_packet)? {
+        let timestamp = *signed_packet.timestamp();
+        let outcome = self.writer.upsert(signed_packet, unmodified_since).await?;
+        if outcome == UpsertOutcome::Stored {
             inc!(Metrics, pkarr_publish_update);
-            self.cache.lock().remove(&pubkey);
-            Ok(true)
+            self.cache.shard(&pubkey).remove(&pubkey);
+            self.notify(pubkey, timestamp, source);
         } else {
             inc!(Metrics, pkarr_publish_noop);
-            Ok(false)
         }
+        Ok(outcome)
+    }
+
+    /// Wake the watchers of a pubkey and send an update to the subscribers.
+    fn notify(&self, pubkey: PublicKeyBytes, timestamp: u64, source: PacketSource) {
+        if let Some(sender) = self.watchers.lock().get(&pubkey) {
+            sender.send_replace(timestamp);
+        }
+        let update = PacketUpdate {
+            pubkey,
+            timestamp,
+            source,
+        };
+        // Fails only if there are no subscribers.
+        self.changes.send(update).ok();
+    }
+
+    /// Remove all packets whose pkarr timestamp is older than `max_age` from the store and the cache.
+    ///
+    /// The packets are removed in chunks of 1000, each in its own write transaction.
+    /// Returns the number of removed packets.
+    pub async fn remove_older_than(&self, max_age: Duration) -> Result<usize> {
+        self.remove_older_than_in_chunks(max_age, GC_CHUNK_SIZE)
+            .await
+    }
+
+    async fn remove_older_than_in_chunks(
+        &self,
+        max_age: Duration,
+        chunk_size: usize,
+    ) -> Result<usize> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+        let cutoff = now.saturating_sub(max_age).as_micros() as u64;
+        let mut count = 0;
+        loop {
+            let removed = self.writer.remove_older_than(cutoff, chunk_size).await?;
+            for pubkey in removed.iter() {
+                self.cache.shard(pubkey).remove(pubkey);
+            }
+            count += removed.len();
+            if removed.len() < chunk_size {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Remove the signed packet for a pubkey from the store and the cache.
+    ///
+    /// Returns whether a packet was removed.
+    pub async fn remove(&self, pubkey: &PublicKeyBytes) -> Result<bool> {
+        let removed = self.writer.remove(pubkey.clone()).await?;
+        self.cache.shard(pubkey).remove(pubkey);
+        Ok(removed)
+    }
+
+    /// List the timestamps and public keys of packets updated at or after `since`, see
+    /// [`PacketStorage::updated_since`].
+    ///
+    /// `since` is a pkarr timestamp, i.e. microseconds since the unix epoch.
+    pub async fn updated_since(
+        &self,
+        since: u64,
+        after: Option<PublicKeyBytes>,
+        limit: usize,
+    ) -> Result<Vec<(u64, PublicKeyBytes)>> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || store.updated_since(since, after.as_ref(), limit))
+            .await?
+    }
+
+    /// List the sequence numbers and public keys of packets stored after the sequence number
+    /// `after`, see [`PacketStorage::updated_after`].
+    pub async fn updated_after(
+        &self,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, PublicKeyBytes)>> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || store.updated_after(after, limit)).await?
+    }
+
+    /// Get a metadata value from the store.
+    pub async fn metadata(&self, key: &str) -> Result<Option<u64>> {
+        let store = self.store.clone();
+        let key = key.to_string();
+        tokio::task::spawn_blocking(move || store.metadata(&key)).await?
+    }
+
+    /// Set a metadata value in the store.
+    pub async fn set_metadata(&self, key: &str, value: u64) -> Result<()> {
+        self.writer.set_metadata(key.to_string(), value).await
+    }
+
+    /// Export all signed packets to a dump file at `path`.
+    ///
+    /// Packets whose signature doesn't verify are skipped, as they would be rejected on import.
+    /// Returns the number of exported packets.
+    pub async fn export(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let path = path.as_ref().to_owned();
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::create(&path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            let mut writer = std::io::BufWriter::new(file);
+            dump::write_header(&mut writer)?;
+            let mut count = 0;
+            for packet in store.iter()? {
+                let bytes = packet?.as_bytes();
+                if let Err(err) = SignedPacket::from_bytes(bytes.clone(), true) {
+                    warn!(?err, "export: skipping packet with an invalid signature");
+                    continue;
+                }
+                dump::write_entry(&mut writer, &bytes[..])?;
+                count += 1;
+            }
+            writer.flush()?;
+            anyhow::Ok(count)
+        })
+        .await?
+    }
+
+    /// Import signed packets from a dump file at `path`.
+    ///
+    /// The signature of each packet is verified, packets that fail to verify are skipped. Valid
+    /// packets are inserted with [`Self::insert`], so packets older than the stored packet for
+    /// their pubkey are skipped as well. Returns the number of packets read, the number of updates
+    /// and the number of invalid packets.
+    pub async fn import(&self, path: impl AsRef<Path>) -> Result<(usize, usize, usize)> {
+        // Number of inserts to run concurrently, so that they are committed in batches.
+        const CHUNK_SIZE: usize = 256;
+        let file = tokio::fs::File::open(path.as_ref())
+            .await
+            .with_context(|| format!("failed to open {}", path.as_ref().display()))?;
+        let mut reader = tokio::io::BufReader::new(file);
+        dump::read_header(&mut reader).await?;
+        let mut count = 0;
+        let mut updated = 0;
+        let mut invalid = 0;
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        loop {
+            let entry = dump::read_entry(&mut reader)
+                .await
+                .with_context(|| format!("failed to read entry {count}"))?;
+            let done = entry.is_none();
+            if let Some(bytes) = entry {
+                count += 1;
+                match SignedPacket::from_bytes(bytes, true) {
+                    Ok(packet) => chunk.push(self.insert(packet, PacketSource::Import)),
+                    Err(err) => {
+                        warn!(entry = count - 1, ?err, "import: skipping invalid packet");
+                        invalid += 1;
+                    }
+                }
+            }
+            if chunk.len() == CHUNK_SIZE || (done && !chunk.is_empty()) {
+                let results = futures::future::try_join_all(chunk.drain(..)).await?;
+                updated += results.into_iter().filter(|updated| *updated).count();
+            }
+            if done {
+                break;
+            }
+        }
+        Ok((count, updated, invalid))
+    }
+
+    /// Publish all packets whose pkarr timestamp is at most `max_age` old to `publisher`.
+    ///
+    /// Returns the number of published packets and the number of failed publishes.
+    pub async fn republish(
+        &self,
+        publisher: Arc<dyn UpstreamPublisher>,
+        max_age: Duration,
+    ) -> Result<(usize, usize)> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+        let cutoff = now.saturating_sub(max_age).as_micros() as u64;
+        let keys = self.updated_since(cutoff, None, usize::MAX).await?;
+        let results = futures::stream::iter(keys)
+            .map(|(_timestamp, pubkey)| {
+                let publisher = publisher.clone();
+                async move {
+                    // The packet might have been updated or removed in the meantime.
+                    let Some(packet) = self.get_signed_packet(&pubkey).await? else {
+                        return anyhow::Ok(None);
+                    };
+                    let res = publisher.publish(&packet).await;
+                    if let Err(err) = &res {
+                        debug!(%pubkey, ?err, "republish failed");
+                    }
+                    Ok(Some(res.is_ok()))
+                }
+            })
+            .buffer_unordered(REPUBLISH_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await?;
+        let published = results.iter().filter(|r| **r == Some(true)).count();
+        let failed = results.iter().filter(|r| **r == Some(false)).count();
+        inc_by!(Metrics, upstream_republish_success, published as u64);
+        inc_by!(Metrics, upstream_republish_error, failed as u64);
+        Ok((published, failed))
+    }
+
+    /// Spawn a task that periodically republishes fresh packets to `publisher`.
+    pub fn spawn_republisher(
+        &self,
+        publisher: Arc<dyn UpstreamPublisher>,
+        options: RepublishOptions,
+    ) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(options.interval);
+            loop {
+                interval.tick().await;
+                match this.republish(publisher.clone(), options.max_age).await {
+                    Ok((published, failed)) => {
+                        debug!(published, failed, "republish: published packets")
+                    }
+                    Err(err) => warn!(?err, "republish: failed to list packets"),
+                }
+            }
+        })
+    }
+
+    /// Spawn a task that periodically evicts stale packets from the store.
+    pub fn spawn_gc(&self, options: GcOptions) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(options.interval);
+            loop {
+                interval.tick().await;
+                match this.remove_older_than(options.max_age).await {
+                    Ok(count) => debug!(count, "gc: removed stale packets"),
+                    Err(err) => warn!(?err, "gc: failed to remove stale packets"),
+                }
+            }
+        })
     }
 }
 
+/// The in-memory zone cache, split into shards that are locked independently.
+///
+/// Even cache hits need exclusive access to update the recency of the LRU, so a single lock
+/// would serialize all DNS lookups.
 #[derive(Debug)]
+struct ShardedZoneCache {
+    shards: Box<[Mutex<ZoneCache>]>,
+}
+
+impl ShardedZoneCache {
+    fn new(shards: usize, cap: usize, max_size: Option<usize>) -> Self {
+        // Don't create more shards than zones fit into the cache.
+        let shards = shards.clamp(1, cap.max(1));
+        let shards = (0..shards)
+            .map(|_| {
+                let cap = cap.div_ceil(shards);
+                let max_size = max_size.map(|max_size| max_size.div_ceil(shards));
+                let not_found_cap = NEGATIVE_CACHE_CAPACITY.div_ceil(shards);
+                Mutex::new(ZoneCache::new(
+                    cap,
+                    max_size,
+                    not_found_cap,
+                    NEGATIVE_CACHE_TTL,
+                ))
+            })
+            .collect();
+        Self { shards }
+    }
+
+    /// Lock the shard responsible for a pubkey.
+    fn shard(&self, pubkey: &PublicKeyBytes) -> MutexGuard<'_, ZoneCache> {
+        // Pubkeys are ed25519 public keys and thus evenly distributed, no need to hash them.
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&pubkey.as_bytes()[..8]);
+        let index = u64::from_le_bytes(prefix) % self.shards.len() as u64;
+        self.shards[index as usize].lock()
+    }
+}
+
+/// A single shard of the [`ShardedZoneCache`].
+#[derive(derive_more::Debug)]
 struct ZoneCache {
     cache: LruCache<PublicKeyBytes, CachedZone>,
+    /// Approximate size of all cached zones in bytes.
+    size: usize,
+    /// Maximum approximate size of all cached zones in bytes.
+    max_size: Option<usize>,
+    /// Pubkeys for which no packet is stored.
+    #[debug("TtlCache")]
+    not_found: TtlCache<PublicKeyBytes, ()>,
+    /// How long pubkeys are kept in [`Self::not_found`].
+    not_found_ttl: Duration,
+    /// When to resolve packets that were resolved from upstream again.
+    revalidate_at: LruCache<PublicKeyBytes, Instant>,
+    /// Incremented whenever a zone is removed, i.e. when a new packet was stored.
+    ///
+    /// Used to not add pubkeys to the negative cache if a packet was stored for them while
+    /// they were looked up.
+    generation: u64,
 }
 
 impl ZoneCache {
-    fn new(cap: usize) -> Self {
-        let cache = LruCache::new(NonZeroUsize::new(cap).expect("capacity must be larger than 0"));
-        Self { cache }
+    fn new(
+        cap: usize,
+        max_size: Option<usize>,
+        not_found_cap: usize,
+        not_found_ttl: Duration,
+    ) -> Self {
+        let cap = NonZeroUsize::new(cap).expect("capacity must be larger than 0");
+        Self {
+            cache: LruCache::new(cap),
+            size: 0,
+            max_size,
+            not_found: TtlCache::new(not_found_cap),
+            not_found_ttl,
+            revalidate_at: LruCache::new(cap),
+            generation: 0,
+        }
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn is_not_found(&self, pubkey: &PublicKeyBytes) -> bool {
+        self.not_found.contains_key(pubkey)
+    }
+
+    fn insert_not_found(&mut self, pubkey: &PublicKeyBytes, generation: u64) {
+        if generation == self.generation {
+            self.not_found
+                .insert(pubkey.clone(), (), self.not_found_ttl);
+        }
+    }
+
+    fn set_revalidate_at(&mut self, pubkey: &PublicKeyBytes, deadline: Instant) {
+        self.revalidate_at.put(pubkey.clone(), deadline);
+    }
+
+    /// Whether a packet resolved from upstream is due to be resolved again.
+    ///
+    /// If so, the deadline is pushed back by [`REVALIDATE_RETRY_INTERVAL`], so that a single
+    /// lookup triggers the revalidation.
+    fn take_revalidation(&mut self, pubkey: &PublicKeyBytes, now: Instant) -> bool {
+        match self.revalidate_at.get_mut(pubkey) {
+            Some(deadline) if *deadline <= now => {
+                *deadline = now + REVALIDATE_RETRY_INTERVAL;
+                true
+            }
+            _ => false,
+        }
     }
 
     fn resolve(
@@ -132,59 +1257,670 @@ impl ZoneCache {
             .and_then(|zone| zone.resolve(name, record_type))
     }
 
+    /// Get the record types at a name, if the zone is cached, see [`CachedZone::record_types`].
+    fn record_types(
+        &mut self,
+        pubkey: &PublicKeyBytes,
+        name: &Name,
+    ) -> Option<Option<Vec<RecordType>>> {
+        self.cache.get(pubkey).map(|zone| zone.record_types(name))
+    }
+
+    fn resolve_signed(
+        &mut self,
+        pubkey: &PublicKeyBytes,
+        key: &SignedRrKey,
+        now: u64,
+    ) -> Option<Arc<RecordSet>> {
+        let zone = self.cache.get(pubkey)?;
+        match zone.signed.get(key) {
+            Some((rset, refresh_at)) if *refresh_at > now => Some(rset.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert_signed(
+        &mut self,
+        pubkey: &PublicKeyBytes,
+        key: SignedRrKey,
+        rset: Arc<RecordSet>,
+        refresh_at: u64,
+        generation: u64,
+    ) {
+        // Don't cache signatures for a zone that was replaced while signing.
+        if generation != self.generation {
+            return;
+        }
+        if let Some(zone) = self.cache.peek_mut(pubkey) {
+            if zone.signed.insert(key, (rset, refresh_at)).is_none() {
+                zone.size += SIGNED_RECORD_SET_SIZE;
+                self.size += SIGNED_RECORD_SET_SIZE;
+                self.evict();
+            }
+        }
+    }
+
+    /// Cache a zone fetched for a lookup and resolve the lookup from it.
+    ///
+    /// The zone is only cached if `generation` is still current, i.e. no packet was stored
+    /// for the pubkey since the fetch started, see [`Self::insert_not_found`].
     fn insert_and_resolve(
         &mut self,
-        signed_packet: &SignedPacket,
+        pubkey: &PublicKeyBytes,
+        zone: CachedZone,
         name: &Name,
         record_type: RecordType,
+        generation: u64,
     ) -> Result<Option<Arc<RecordSet>>> {
-        let pubkey = PublicKeyBytes::from_signed_packet(signed_packet);
-        self.insert(signed_packet)?;
-        Ok(self.resolve(&pubkey, name, record_type))
+        if generation != self.generation {
+            return Ok(zone.resolve(name, record_type));
+        }
+        self.insert(pubkey, zone);
+        Ok(self.resolve(pubkey, name, record_type))
     }
 
-    fn insert(&mut self, signed_packet: &SignedPacket) -> Result<()> {
-        let pubkey = PublicKeyBytes::from_signed_packet(signed_packet);
+    /// Like [`Self::insert_and_resolve`], but get the record types at a name.
+    fn insert_and_get_record_types(
+        &mut self,
+        pubkey: &PublicKeyBytes,
+        zone: CachedZone,
+        name: &Name,
+        generation: u64,
+    ) -> Result<Option<Vec<RecordType>>> {
+        if generation != self.generation {
+            return Ok(zone.record_types(name));
+        }
+        self.insert(pubkey, zone);
+        Ok(self.record_types(pubkey, name).flatten())
+    }
+
+    fn insert(&mut self, pubkey: &PublicKeyBytes, zone: CachedZone) {
         if self
             .cache
-            .peek(&pubkey)
-            .map(|old| old.is_newer_than(signed_packet))
+            .peek(pubkey)
+            .map(|old| old.is_newer_than(&zone))
             .unwrap_or(false)
         {
-            return Ok(());
+            return;
         }
-        self.cache
-            .put(pubkey, CachedZone::from_signed_packet(signed_packet)?);
-        Ok(())
+        let pubkey = pubkey.clone();
+        self.size += zone.size;
+        if let Some((old_pubkey, old)) = self.cache.push(pubkey.clone(), zone) {
+            self.size -= old.size;
+            if old_pubkey != pubkey {
+                inc!(Metrics, store_cache_evictions);
+            }
+        }
+        self.evict();
+    }
+
+    /// Evict the least recently used zones until the cache fits into [`Self::max_size`].
+    ///
+    /// The most recently used zone is always kept.
+    fn evict(&mut self) {
+        let Some(max_size) = self.max_size else {
+            return;
+        };
+        let mut evicted = 0;
+        while self.size > max_size && self.cache.len() > 1 {
+            let Some((_pubkey, old)) = self.cache.pop_lru() else {
+                break;
+            };
+            self.size -= old.size;
+            evicted += 1;
+        }
+        inc_by!(Metrics, store_cache_evictions, evicted);
     }
 
     fn remove(&mut self, pubkey: &PublicKeyBytes) {
-        self.cache.pop(pubkey);
+        if let Some(old) = self.cache.pop(pubkey) {
+            self.size -= old.size;
+        }
+        self.not_found.remove(pubkey);
+        self.revalidate_at.pop(pubkey);
+        self.generation += 1;
     }
 }
 
+/// The time at which a packet resolved from upstream is resolved again: after its minimum TTL,
+/// but not earlier than after one second.
+fn revalidation_deadline(packet: &SignedPacket) -> Instant {
+    let min_ttl = packet.packet().answers.iter().map(|rr| rr.ttl).min();
+    let min_ttl = min_ttl.unwrap_or_default().max(1);
+    Instant::now() + Duration::from_secs(min_ttl as u64)
+}
+
+/// Key of a signed record set in a [`CachedZone`]: the origin and the name below it.
+type SignedRrKey = (LowerName, RrKey);
+
+/// Approximate memory usage of a signed record set in bytes.
+const SIGNED_RECORD_SET_SIZE: usize = 512;
+
 #[derive(Debug)]
 struct CachedZone {
     timestamp: u64,
     records: BTreeMap<RrKey, Arc<RecordSet>>,
+    /// Record sets with full names and DNSSEC signatures, with the time at which to refresh them.
+    signed: BTreeMap<SignedRrKey, (Arc<RecordSet>, u64)>,
+    /// Approximate memory usage in bytes.
+    size: usize,
 }
 
 impl CachedZone {
     fn from_signed_packet(signed_packet: &SignedPacket) -> Result<Self> {
+        let pubkey = PublicKeyBytes::from_signed_packet(signed_packet);
+        let encoded = signed_packet.encoded_packet();
+        Self::from_encoded_packet(&pubkey, *signed_packet.timestamp(), &encoded)
+    }
+
+    /// The zone of a [`ZoneUpdate`], which replaces the zone of the signed packet it applies to.
+    fn from_zone_update(update: &ZoneUpdate) -> Result<Self> {
+        Self::from_encoded_packet(
+            &update.pubkey,
+            update.base_timestamp,
+            &update.encoded_packet,
+        )
+    }
+
+    fn from_encoded_packet(
+        pubkey: &PublicKeyBytes,
+        timestamp: u64,
+        encoded_packet: &[u8],
+    ) -> Result<Self> {
         let (_label, records) =
-            signed_packet_to_hickory_records_without_origin(signed_packet, |_| true)?;
+            encoded_packet_to_hickory_records_without_origin(pubkey, encoded_packet, |_| true)?;
+        // The parsed records take up several times the size of the encoded packet.
+        let size = 256 + 4 * encoded_packet.len();
         Ok(Self {
             records,
-            timestamp: *signed_packet.timestamp(),
+            signed: BTreeMap::new(),
+            timestamp,
+            size,
         })
     }
 
-    fn is_newer_than(&self, signed_packet: &SignedPacket) -> bool {
-        self.timestamp > *signed_packet.timestamp()
+    fn is_newer_than(&self, zone: &CachedZone) -> bool {
+        self.timestamp > zone.timestamp
     }
 
     fn resolve(&self, name: &Name, record_type: RecordType) -> Option<Arc<RecordSet>> {
         let key = RrKey::new(name.into(), record_type);
         self.records.get(&key).cloned()
     }
+
+    /// Get the record types at a name, or `None` if neither the name nor any name below it
+    /// has records.
+    fn record_types(&self, name: &Name) -> Option<Vec<RecordType>> {
+        let name = LowerName::from(name);
+        let mut exists = false;
+        let mut types = vec![];
+        for key in self.records.keys() {
+            if key.name == name {
+                types.push(key.record_type);
+                exists = true;
+            } else if name.zone_of(&key.name) {
+                exists = true;
+            }
+        }
+        exists.then_some(types)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use hickory_proto::{
+        op::Message,
+        rr::{LowerName, Name, RecordType, RrKey},
+    };
+    use iroh_net::dns::node_info::IROH_TXT_NAME;
+
+    use super::{
+        dump, CachedZone, InMemoryPacketStore, PacketSource, PublicKeyBytes, SignedPacketStore,
+        UpsertOutcome, ZoneCache, ZoneStore, ZoneUpdate, NEGATIVE_CACHE_TTL,
+        SIGNED_RECORD_SET_SIZE,
+    };
+    use crate::test_utils::{
+        copy_packet, forge_packet, metrics, node_packet, random_node_packet, TempPath,
+    };
+
+    #[tokio::test]
+    async fn remove_stale_packets() -> Result<()> {
+        let metrics = metrics();
+        let stores = [
+            ZoneStore::new(InMemoryPacketStore::new())?,
+            ZoneStore::new(SignedPacketStore::in_memory()?)?,
+        ];
+        for store in stores {
+            let (_secret_key, packet) = random_node_packet()?;
+            let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+            let name = Name::from_utf8(IROH_TXT_NAME)?;
+            store.insert(packet, PacketSource::PkarrPublish).await?;
+            // Load the zone into the cache.
+            assert!(store
+                .resolve(&pubkey, &name, RecordType::TXT)
+                .await?
+                .is_some());
+
+            // The packet is not older than the max age.
+            assert_eq!(store.remove_older_than(Duration::from_secs(60)).await?, 0);
+            assert!(store.get_signed_packet(&pubkey).await?.is_some());
+
+            let removed = metrics.store_packets_removed.get();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            assert_eq!(store.remove_older_than(Duration::ZERO).await?, 1);
+            assert!(metrics.store_packets_removed.get() > removed);
+
+            // The packet is gone from both the storage and the cache.
+            assert!(store.get_signed_packet(&pubkey).await?.is_none());
+            assert!(store
+                .resolve(&pubkey, &name, RecordType::TXT)
+                .await?
+                .is_none());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_stale_packets_in_chunks() -> Result<()> {
+        let stores = [
+            ZoneStore::new(InMemoryPacketStore::new())?,
+            ZoneStore::new(SignedPacketStore::in_memory()?)?,
+        ];
+        for store in stores {
+            let mut keys = vec![];
+            for _ in 0..5 {
+                let (_secret_key, packet) = random_node_packet()?;
+                keys.push(PublicKeyBytes::from_signed_packet(&packet));
+                store.insert(packet, PacketSource::PkarrPublish).await?;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+
+            // A chunk removes at most the chunk size.
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+            let cutoff = now.as_micros() as u64;
+            assert_eq!(store.writer.remove_older_than(cutoff, 2).await?.len(), 2);
+
+            // The remaining packets are removed in further chunks.
+            assert_eq!(
+                store.remove_older_than_in_chunks(Duration::ZERO, 2).await?,
+                3
+            );
+            for key in keys.iter() {
+                assert!(store.get_signed_packet(key).await?.is_none());
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribe_to_updates() -> Result<()> {
+        let store = ZoneStore::in_memory()?;
+        let mut updates = store.subscribe();
+
+        let (_secret_key, packet) = random_node_packet()?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+        let timestamp = *packet.timestamp();
+        assert!(
+            store
+                .insert(copy_packet(&packet), PacketSource::PkarrPublish)
+                .await?
+        );
+
+        let update = updates.recv().await?;
+        assert_eq!(update.pubkey, pubkey);
+        assert_eq!(update.timestamp, timestamp);
+        assert_eq!(update.source, PacketSource::PkarrPublish);
+
+        // Inserting the same packet again is not an update.
+        assert!(!store.insert(packet, PacketSource::Import).await?);
+        assert!(updates.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_single_pubkey() -> Result<()> {
+        let store = ZoneStore::in_memory()?;
+        let (_secret_key, packet) = random_node_packet()?;
+        let (_secret_key, other) = random_node_packet()?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+        let timestamp = *packet.timestamp();
+        let mut watcher = store.watch(&pubkey);
+
+        // Updates of other pubkeys don't wake the watcher.
+        store.insert(other, PacketSource::PkarrPublish).await?;
+        let changed = watcher.changed();
+        assert!(tokio::time::timeout(Duration::from_millis(50), changed)
+            .await
+            .is_err());
+
+        store.insert(packet, PacketSource::PkarrPublish).await?;
+        assert_eq!(watcher.changed().await, Some(timestamp));
+
+        // The channel of the pubkey is removed with its last watcher.
+        let second = store.watch(&pubkey);
+        drop(watcher);
+        assert!(store.watchers.lock().contains_key(&pubkey));
+        drop(second);
+        assert!(store.watchers.lock().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_conditional() -> Result<()> {
+        let stores = [
+            ZoneStore::new(InMemoryPacketStore::new())?,
+            ZoneStore::new(SignedPacketStore::in_memory()?)?,
+        ];
+        for store in stores {
+            let (secret_key, old) = random_node_packet()?;
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            let new = node_packet(&secret_key)?;
+            let old_timestamp = *old.timestamp();
+
+            let source = PacketSource::PkarrPublish;
+            let outcome = store
+                .insert_conditional(copy_packet(&old), source, None)
+                .await?;
+            assert_eq!(outcome, UpsertOutcome::Stored);
+            let outcome = store
+                .insert_conditional(copy_packet(&new), source, Some(old_timestamp - 1))
+                .await?;
+            assert_eq!(outcome, UpsertOutcome::PreconditionFailed);
+            let outcome = store
+                .insert_conditional(copy_packet(&new), source, Some(old_timestamp))
+                .await?;
+            assert_eq!(outcome, UpsertOutcome::Stored);
+            let outcome = store.insert_conditional(old, source, None).await?;
+            assert_eq!(outcome, UpsertOutcome::Outdated);
+            let outcome = store.insert_conditional(new, source, None).await?;
+            assert_eq!(outcome, UpsertOutcome::Unchanged);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn zone_updates() -> Result<()> {
+        let stores = [
+            ZoneStore::new(InMemoryPacketStore::new())?,
+            ZoneStore::new(SignedPacketStore::in_memory()?)?,
+        ];
+        for store in stores {
+            let (secret_key, packet) = random_node_packet()?;
+            let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+            let name = Name::from_utf8(IROH_TXT_NAME)?;
+            let base_timestamp = *packet.timestamp();
+            store.insert(packet, PacketSource::PkarrPublish).await?;
+
+            // A zone without records replaces the zone of the signed packet over DNS.
+            let update = ZoneUpdate {
+                pubkey: pubkey.clone(),
+                base_timestamp,
+                timestamp: 1,
+                encoded_packet: Message::new().to_vec()?.into(),
+            };
+            let mut updates = store.subscribe();
+            let mut watcher = store.watch(&pubkey);
+            let outcome = store
+                .insert_zone_update(update.clone(), Some(0), PacketSource::DnsUpdate)
+                .await?;
+            assert_eq!(outcome, UpsertOutcome::PreconditionFailed);
+            let outcome = store
+                .insert_zone_update(update.clone(), None, PacketSource::DnsUpdate)
+                .await?;
+            assert_eq!(outcome, UpsertOutcome::Stored);
+            // Subscribers and watchers are notified of stored updates only.
+            let change = updates.recv().await?;
+            assert_eq!(change.timestamp, 1);
+            assert_eq!(change.source, PacketSource::DnsUpdate);
+            assert!(updates.try_recv().is_err());
+            assert_eq!(watcher.changed().await, Some(1));
+            assert!(store
+                .resolve(&pubkey, &name, RecordType::TXT)
+                .await?
+                .is_none());
+            // The signed packet is kept.
+            let stored = store.get_signed_packet(&pubkey).await?;
+            assert_eq!(stored.map(|p| *p.timestamp()), Some(base_timestamp));
+
+            // Updates must apply to the stored packet and the previous update.
+            let next = ZoneUpdate {
+                timestamp: 2,
+                ..update.clone()
+            };
+            let outcome = store
+                .insert_zone_update(next.clone(), None, PacketSource::DnsUpdate)
+                .await?;
+            assert_eq!(outcome, UpsertOutcome::PreconditionFailed);
+            let stale = ZoneUpdate {
+                base_timestamp: 0,
+                ..next.clone()
+            };
+            let outcome = store
+                .insert_zone_update(stale, Some(1), PacketSource::DnsUpdate)
+                .await?;
+            assert_eq!(outcome, UpsertOutcome::PreconditionFailed);
+            let outcome = store
+                .insert_zone_update(next, Some(1), PacketSource::DnsUpdate)
+                .await?;
+            assert_eq!(outcome, UpsertOutcome::Stored);
+
+            // A newer signed packet drops the update.
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            let packet = node_packet(&secret_key)?;
+            store.insert(packet, PacketSource::PkarrPublish).await?;
+            assert!(store.get_zone_update(&pubkey).await?.is_none());
+            assert!(store
+                .resolve(&pubkey, &name, RecordType::TXT)
+                .await?
+                .is_some());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_import_roundtrip() -> Result<()> {
+        let path = TempPath::new("dump");
+        let source = ZoneStore::in_memory()?;
+        let (_, packet_a) = random_node_packet()?;
+        let (secret_key_b, old_b) = random_node_packet()?;
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let new_b = node_packet(&secret_key_b)?;
+        source
+            .insert(copy_packet(&packet_a), PacketSource::PkarrPublish)
+            .await?;
+        source.insert(old_b, PacketSource::PkarrPublish).await?;
+        assert_eq!(source.export(&path).await?, 2);
+
+        // The target already has a newer packet for b, which is kept.
+        let target = ZoneStore::in_memory()?;
+        target
+            .insert(copy_packet(&new_b), PacketSource::PkarrPublish)
+            .await?;
+        assert_eq!(target.import(&path).await?, (2, 1, 0));
+        let key_a = PublicKeyBytes::from_signed_packet(&packet_a);
+        let key_b = PublicKeyBytes::from_signed_packet(&new_b);
+        let imported = target.get_signed_packet(&key_a).await?;
+        assert_eq!(
+            imported.map(|p| p.as_bytes().clone()),
+            Some(packet_a.as_bytes().clone())
+        );
+        let kept = target.get_signed_packet(&key_b).await?;
+        assert_eq!(
+            kept.map(|p| p.as_bytes().clone()),
+            Some(new_b.as_bytes().clone())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_import_skip_invalid_signatures() -> Result<()> {
+        let path = TempPath::new("dump");
+        let (_, valid) = random_node_packet()?;
+        let (_, packet) = random_node_packet()?;
+        let forged = forge_packet(&packet)?;
+        let key_forged = PublicKeyBytes::from_signed_packet(&forged);
+
+        // Packets in the store that don't verify are not exported.
+        let source = ZoneStore::in_memory()?;
+        source
+            .insert(copy_packet(&valid), PacketSource::PkarrPublish)
+            .await?;
+        source
+            .insert(copy_packet(&forged), PacketSource::PkarrPublish)
+            .await?;
+        assert_eq!(source.export(&path).await?, 1);
+
+        // Forged entries in a dump are not imported.
+        let mut file = std::fs::File::create(&path)?;
+        dump::write_header(&mut file)?;
+        dump::write_entry(&mut file, &valid.as_bytes()[..])?;
+        dump::write_entry(&mut file, &forged.as_bytes()[..])?;
+        drop(file);
+        let target = ZoneStore::in_memory()?;
+        assert_eq!(target.import(&path).await?, (2, 1, 1));
+        assert!(target.get_signed_packet(&key_forged).await?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_caching_outdated_fetch() -> Result<()> {
+        let mut cache = ZoneCache::new(16, None, 16, NEGATIVE_CACHE_TTL);
+        let (_secret_key, packet) = random_node_packet()?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+        let name = Name::from_utf8(IROH_TXT_NAME)?;
+
+        let zone = || CachedZone::from_signed_packet(&packet);
+
+        // A packet is stored while the lookup fetches the previous one.
+        let generation = cache.generation();
+        cache.remove(&pubkey);
+        let rset =
+            cache.insert_and_resolve(&pubkey, zone()?, &name, RecordType::TXT, generation)?;
+        assert!(rset.is_some());
+        assert!(cache.resolve(&pubkey, &name, RecordType::TXT).is_none());
+
+        let generation = cache.generation();
+        cache.insert_and_resolve(&pubkey, zone()?, &name, RecordType::TXT, generation)?;
+        assert!(cache.resolve(&pubkey, &name, RecordType::TXT).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn negative_cache() -> Result<()> {
+        let metrics = metrics();
+        let store = ZoneStore::in_memory()?;
+        let (_secret_key, packet) = random_node_packet()?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+        let name = Name::from_utf8(IROH_TXT_NAME)?;
+
+        // The first miss is cached, the second is answered from the cache.
+        assert!(store
+            .resolve(&pubkey, &name, RecordType::TXT)
+            .await?
+            .is_none());
+        let negative_hits = metrics.store_cache_negative_hits.get();
+        assert!(store
+            .resolve(&pubkey, &name, RecordType::TXT)
+            .await?
+            .is_none());
+        assert!(metrics.store_cache_negative_hits.get() > negative_hits);
+
+        // Storing a packet clears the cached miss.
+        store.insert(packet, PacketSource::PkarrPublish).await?;
+        assert!(store
+            .resolve(&pubkey, &name, RecordType::TXT)
+            .await?
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn negative_cache_expiry() -> Result<()> {
+        let ttl = Duration::from_millis(50);
+        let mut cache = ZoneCache::new(16, None, 16, ttl);
+        let (_secret_key, packet) = random_node_packet()?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+
+        cache.insert_not_found(&pubkey, cache.generation());
+        assert!(cache.is_not_found(&pubkey));
+        std::thread::sleep(ttl * 2);
+        assert!(!cache.is_not_found(&pubkey));
+
+        // A miss from before a packet was stored is not cached.
+        let generation = cache.generation();
+        cache.remove(&pubkey);
+        cache.insert_not_found(&pubkey, generation);
+        assert!(!cache.is_not_found(&pubkey));
+        Ok(())
+    }
+
+    #[test]
+    fn evict_by_size() -> Result<()> {
+        let metrics = metrics();
+        let name = Name::from_utf8(IROH_TXT_NAME)?;
+        let packets = (0..3)
+            .map(|_| Ok(random_node_packet()?.1))
+            .collect::<Result<Vec<_>>>()?;
+        let pubkeys: Vec<_> = packets
+            .iter()
+            .map(PublicKeyBytes::from_signed_packet)
+            .collect();
+        let zone_size = CachedZone::from_signed_packet(&packets[0])?.size;
+
+        // Room for two zones, but not for a signature on top.
+        let max_size = 2 * zone_size + SIGNED_RECORD_SET_SIZE / 2;
+        let mut cache = ZoneCache::new(16, Some(max_size), 16, NEGATIVE_CACHE_TTL);
+        let evictions = metrics.store_cache_evictions.get();
+        for (pubkey, packet) in pubkeys.iter().zip(&packets) {
+            cache.insert(pubkey, CachedZone::from_signed_packet(packet)?);
+        }
+        assert!(cache.size <= max_size);
+        assert!(cache.resolve(&pubkeys[0], &name, RecordType::TXT).is_none());
+        assert!(metrics.store_cache_evictions.get() > evictions);
+
+        // Caching a signature also evicts the least recently used zone.
+        let rset = cache
+            .resolve(&pubkeys[2], &name, RecordType::TXT)
+            .expect("zone is cached");
+        let key = (
+            LowerName::from(Name::root()),
+            RrKey::new(name.clone().into(), RecordType::TXT),
+        );
+        let generation = cache.generation();
+        cache.insert_signed(&pubkeys[2], key, rset, u64::MAX, generation);
+        assert!(cache.size <= max_size);
+        assert!(cache.resolve(&pubkeys[1], &name, RecordType::TXT).is_none());
+        assert!(cache.resolve(&pubkeys[2], &name, RecordType::TXT).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cache_hits_and_misses() -> Result<()> {
+        let metrics = metrics();
+        let store = ZoneStore::in_memory()?;
+        let (_secret_key, packet) = random_node_packet()?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+        let name = Name::from_utf8(IROH_TXT_NAME)?;
+        store.insert(packet, PacketSource::PkarrPublish).await?;
+
+        let misses = metrics.store_cache_misses.get();
+        assert!(store
+            .resolve(&pubkey, &name, RecordType::TXT)
+            .await?
+            .is_some());
+        assert!(metrics.store_cache_misses.get() > misses);
+
+        let hits = metrics.store_cache_hits.get();
+        assert!(store
+            .resolve(&pubkey, &name, RecordType::TXT)
+            .await?
+            .is_some());
+        assert!(metrics.store_cache_hits.get() > hits);
+        Ok(())
+    }
 }
@@ -0,0 +1,60 @@
+//! A portable dump format for signed packets.
+//!
+//! A dump starts with the [`MAGIC`] bytes, followed by any number of entries. Each entry is a
+//! big-endian `u32` length prefix followed by the bytes of a [`SignedPacket`] as returned by
+//! [`SignedPacket::as_bytes`], i.e. the public key, signature, timestamp and encoded DNS packet.
+//!
+//! Dumps are not authenticated, so the signature of each entry must be verified on import.
+
+use std::io::{self, Write};
+
+use anyhow::{ensure, Context, Result};
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Magic bytes at the start of every dump, including the format version.
+pub const MAGIC: &[u8; 8] = b"pkdump01";
+
+/// Upper bound for the size of a single entry.
+///
+/// Signed packets are at most 1104 bytes, this leaves some headroom.
+const MAX_ENTRY_SIZE: usize = 4096;
+
+/// Write the dump header.
+pub fn write_header(writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(MAGIC)
+}
+
+/// Write a single encoded signed packet.
+pub fn write_entry(writer: &mut impl Write, packet_bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(packet_bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(packet_bytes)
+}
+
+/// Read and check the dump header.
+pub async fn read_header(reader: &mut (impl AsyncRead + Unpin)) -> Result<()> {
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .await
+        .context("failed to read dump header")?;
+    ensure!(&magic == MAGIC, "not a signed packet dump");
+    Ok(())
+}
+
+/// Read the next encoded signed packet, or `None` at the end of the dump.
+///
+/// The entry is not decoded, see [`SignedPacket::from_bytes`](pkarr::SignedPacket::from_bytes).
+pub async fn read_entry(reader: &mut (impl AsyncRead + Unpin)) -> Result<Option<Bytes>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    ensure!(len <= MAX_ENTRY_SIZE, "dump entry too large ({len} bytes)");
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    Ok(Some(buf.into()))
+}
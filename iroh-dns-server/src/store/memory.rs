@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use bytes::Bytes;
+use iroh_metrics::{inc, inc_by};
+use parking_lot::Mutex;
+use pkarr::SignedPacket;
+
+use super::{PacketIter, PacketStorage, UpsertOutcome, ZoneUpdate};
+use crate::{metrics::Metrics, util::PublicKeyBytes};
+
+/// A [`PacketStorage`] that keeps all packets in memory.
+///
+/// Nothing is persisted, and no history is kept. This is mostly useful for tests.
+#[derive(Debug, Default)]
+pub struct InMemoryPacketStore {
+    packets: Mutex<BTreeMap<PublicKeyBytes, Bytes>>,
+    /// Always locked after [`Self::packets`].
+    sequences: Mutex<Sequences>,
+    /// Always locked after [`Self::packets`].
+    updates: Mutex<BTreeMap<PublicKeyBytes, ZoneUpdate>>,
+    metadata: Mutex<BTreeMap<String, u64>>,
+}
+
+/// The sequence numbers of the stored packets, see [`PacketStorage::updated_after`].
+#[derive(Debug, Default)]
+struct Sequences {
+    by_key: BTreeMap<PublicKeyBytes, u64>,
+    by_sequence: BTreeMap<u64, PublicKeyBytes>,
+    last: u64,
+}
+
+impl Sequences {
+    /// Assign the next sequence number to a public key.
+    fn assign(&mut self, key: &PublicKeyBytes) {
+        self.remove(key);
+        self.last += 1;
+        self.by_key.insert(key.clone(), self.last);
+        self.by_sequence.insert(self.last, key.clone());
+    }
+
+    fn remove(&mut self, key: &PublicKeyBytes) {
+        if let Some(sequence) = self.by_key.remove(key) {
+            self.by_sequence.remove(&sequence);
+        }
+    }
+}
+
+impl InMemoryPacketStore {
+    /// Create a new, empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PacketStorage for InMemoryPacketStore {
+    fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        let bytes = self.packets.lock().get(key).cloned();
+        bytes
+            .map(|bytes| SignedPacket::from_bytes(bytes, false).map_err(anyhow::Error::from))
+            .transpose()
+    }
+
+    fn upsert(&self, packet: SignedPacket, unmodified_since: Option<u64>) -> Result<UpsertOutcome> {
+        let key = PublicKeyBytes::from_signed_packet(&packet);
+        let mut packets = self.packets.lock();
+        let replaced = match packets.get(&key) {
+            Some(existing) => {
+                let existing = SignedPacket::from_bytes(existing.clone(), false)?;
+                if let Some(outcome) = UpsertOutcome::check(&existing, &packet, unmodified_since) {
+                    return Ok(outcome);
+                }
+                true
+            }
+            None => false,
+        };
+        self.sequences.lock().assign(&key);
+        self.updates.lock().remove(&key);
+        packets.insert(key, Bytes::copy_from_slice(&packet.as_bytes()[..]));
+        if replaced {
+            inc!(Metrics, store_packets_updated);
+        } else {
+            inc!(Metrics, store_packets_inserted);
+        }
+        Ok(UpsertOutcome::Stored)
+    }
+
+    fn remove(&self, key: &PublicKeyBytes) -> Result<bool> {
+        let mut packets = self.packets.lock();
+        let removed = packets.remove(key).is_some();
+        self.sequences.lock().remove(key);
+        self.updates.lock().remove(key);
+        if removed {
+            inc!(Metrics, store_packets_removed);
+        }
+        Ok(removed)
+    }
+
+    fn remove_older_than(&self, cutoff: u64, limit: usize) -> Result<Vec<PublicKeyBytes>> {
+        let mut packets = self.packets.lock();
+        let mut sequences = self.sequences.lock();
+        let mut updates = self.updates.lock();
+        let mut expired = vec![];
+        for (key, bytes) in packets.iter() {
+            let packet = SignedPacket::from_bytes(bytes.clone(), false)?;
+            if *packet.timestamp() < cutoff {
+                expired.push(key.clone());
+                if expired.len() == limit {
+                    break;
+                }
+            }
+        }
+        for key in expired.iter() {
+            packets.remove(key);
+            sequences.remove(key);
+            updates.remove(key);
+        }
+        inc_by!(Metrics, store_packets_removed, expired.len() as u64);
+        let mut removed = expired;
+        updates.retain(|key, update| {
+            let keep = update.timestamp >= cutoff || removed.len() == limit;
+            if !keep {
+                removed.push(key.clone());
+            }
+            keep
+        });
+        Ok(removed)
+    }
+
+    fn iter(&self) -> Result<PacketIter<'_>> {
+        let packets: Vec<Bytes> = self.packets.lock().values().cloned().collect();
+        let iter = packets
+            .into_iter()
+            .map(|bytes| SignedPacket::from_bytes(bytes, false).map_err(anyhow::Error::from));
+        Ok(Box::new(iter))
+    }
+
+    fn updated_after(&self, after: u64, limit: usize) -> Result<Vec<(u64, PublicKeyBytes)>> {
+        let sequences = self.sequences.lock();
+        let entries = sequences
+            .by_sequence
+            .range(after.saturating_add(1)..)
+            .take(limit)
+            .map(|(sequence, key)| (*sequence, key.clone()))
+            .collect();
+        Ok(entries)
+    }
+
+    fn get_zone_update(&self, key: &PublicKeyBytes) -> Result<Option<ZoneUpdate>> {
+        Ok(self.updates.lock().get(key).cloned())
+    }
+
+    fn put_zone_update(&self, update: ZoneUpdate, previous: Option<u64>) -> Result<UpsertOutcome> {
+        let packets = self.packets.lock();
+        let packet = packets
+            .get(&update.pubkey)
+            .map(|bytes| SignedPacket::from_bytes(bytes.clone(), false))
+            .transpose()?;
+        let mut sequences = self.sequences.lock();
+        let mut updates = self.updates.lock();
+        let current = updates
+            .get(&update.pubkey)
+            .filter(|current| current.applies_to(packet.as_ref()))
+            .map(|current| current.timestamp);
+        if !update.applies_to(packet.as_ref()) || current != previous {
+            return Ok(UpsertOutcome::PreconditionFailed);
+        }
+        sequences.assign(&update.pubkey);
+        updates.insert(update.pubkey.clone(), update);
+        Ok(UpsertOutcome::Stored)
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<u64>> {
+        Ok(self.metadata.lock().get(key).copied())
+    }
+
+    fn set_metadata(&self, key: &str, value: u64) -> Result<()> {
+        self.metadata.lock().insert(key.to_string(), value);
+        Ok(())
+    }
+}
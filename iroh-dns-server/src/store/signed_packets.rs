@@ -1,22 +1,81 @@
-use std::path::Path;
+use std::{ops::Bound, path::Path};
 
-use anyhow::Result;
-use iroh_metrics::inc;
+use anyhow::{bail, ensure, Result};
+use iroh_metrics::{inc, inc_by};
 use pkarr::SignedPacket;
-use redb::{backends::InMemoryBackend, Database, ReadableTable, TableDefinition};
+use redb::{
+    backends::InMemoryBackend, Database, ReadableTable, Table, TableDefinition, TableHandle,
+    WriteTransaction,
+};
+use tracing::{info, warn};
 
+use super::{PacketIter, PacketStorage, UpsertOutcome, ZoneUpdate};
 use crate::{metrics::Metrics, util::PublicKeyBytes};
 
+/// The current version of the database schema.
+///
+/// Bump this and add a migration to [`migrate`] whenever the layout of the tables changes.
+pub const SCHEMA_VERSION: u64 = 2;
+
+/// Metadata about the database, e.g. the schema version.
+const METADATA_TABLE: TableDefinition<&str, u64> = TableDefinition::new("metadata");
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+/// The last assigned sequence number, see [`SEQUENCE_TABLE`].
+const LAST_SEQUENCE_KEY: &str = "last_sequence";
+/// The history size the [`HISTORY_TABLE`] was last trimmed to.
+const HISTORY_SIZE_KEY: &str = "history_size";
+
 pub type SignedPacketsKey = [u8; 32];
 const SIGNED_PACKETS_TABLE: TableDefinition<&SignedPacketsKey, &[u8]> =
     TableDefinition::new("signed-packets-1");
 
+/// Index of the pkarr timestamp of each stored packet.
+///
+/// Keyed by `(timestamp, pubkey)`, so that range queries by age don't need to scan the full
+/// [`SIGNED_PACKETS_TABLE`].
+pub type TimestampIndexKey<'a> = (u64, &'a SignedPacketsKey);
+const TIMESTAMP_INDEX_TABLE: TableDefinition<TimestampIndexKey<'static>, ()> =
+    TableDefinition::new("signed-packets-by-timestamp-1");
+
+/// The sequence number at which the current packet or zone update of each public key was stored,
+/// see [`PacketStorage::updated_after`].
+const SEQUENCE_TABLE: TableDefinition<&SignedPacketsKey, u64> =
+    TableDefinition::new("signed-packets-sequence-1");
+
+/// Index of the [`SEQUENCE_TABLE`] by sequence number.
+const SEQUENCE_INDEX_TABLE: TableDefinition<u64, &SignedPacketsKey> =
+    TableDefinition::new("signed-packets-by-sequence-1");
+
+/// The most recent packets for each public key.
+///
+/// Keyed by `(pubkey, timestamp)`, trimmed to the configured history size on each update, and
+/// for all pubkeys when the configured history size is lowered.
+pub type HistoryKey<'a> = (&'a SignedPacketsKey, u64);
+const HISTORY_TABLE: TableDefinition<HistoryKey<'static>, &[u8]> =
+    TableDefinition::new("signed-packets-history-1");
+
+/// Zones changed with a DNS UPDATE, see [`ZoneUpdate`].
+///
+/// The value is the big-endian base timestamp and timestamp of the update, followed by the
+/// encoded DNS packet.
+const ZONE_UPDATES_TABLE: TableDefinition<&SignedPacketsKey, &[u8]> =
+    TableDefinition::new("zone-updates-1");
+
+/// Don't keep a history of packets by default
+pub const DEFAULT_HISTORY_SIZE: usize = 0;
+
+/// The smallest public key, used to build range bounds on the [`TIMESTAMP_INDEX_TABLE`].
+const MIN_KEY: SignedPacketsKey = [0u8; 32];
+
+/// A [`PacketStorage`] backed by a [redb](https://docs.rs/redb) database.
 #[derive(Debug)]
 pub struct SignedPacketStore {
     db: Database,
+    history_size: usize,
 }
 
 impl SignedPacketStore {
+    /// Open or create a database file at `path`.
     pub fn persistent(path: impl AsRef<Path>) -> Result<Self> {
         if let Some(parent) = path.as_ref().parent() {
             std::fs::create_dir_all(parent)?;
@@ -25,58 +84,127 @@ impl SignedPacketStore {
         Self::open(db)
     }
 
+    /// Create a database that is kept in memory.
     pub fn in_memory() -> Result<Self> {
         let db = Database::builder().create_with_backend(InMemoryBackend::new())?;
         Self::open(db)
     }
 
+    /// Open a store on an existing [`Database`], migrating it to the latest schema version.
     pub fn open(db: Database) -> Result<Self> {
         let write_tx = db.begin_write()?;
-        {
-            let _table = write_tx.open_table(SIGNED_PACKETS_TABLE)?;
-        }
+        migrate(&write_tx)?;
         write_tx.commit()?;
-        Ok(Self { db })
+        Ok(Self {
+            db,
+            history_size: DEFAULT_HISTORY_SIZE,
+        })
     }
 
-    pub fn upsert(&self, packet: SignedPacket) -> Result<bool> {
-        let key = PublicKeyBytes::from_signed_packet(&packet);
+    /// Set the number of packets to keep in the history of each public key.
+    ///
+    /// Set to 0 to disable the history, in which case [`PacketStorage::history`] only returns the
+    /// current packet. If the size is lower than the one the store was last used with, the
+    /// histories of all public keys are trimmed to the new size.
+    pub fn with_history_size(mut self, history_size: usize) -> Result<Self> {
         let tx = self.db.begin_write()?;
-        let mut replaced = false;
         {
-            let mut table = tx.open_table(SIGNED_PACKETS_TABLE)?;
-            if let Some(existing) = get_packet(&table, &key)? {
-                if existing.more_recent_than(&packet) {
-                    return Ok(false);
-                } else {
-                    replaced = true;
+            let mut metadata = tx.open_table(METADATA_TABLE)?;
+            let previous = metadata.get(HISTORY_SIZE_KEY)?.map(|v| v.value());
+            // Databases without a recorded size may hold histories of any size.
+            if previous.is_none_or(|previous| (history_size as u64) < previous) {
+                let mut history = tx.open_table(HISTORY_TABLE)?;
+                let removed = trim_all_histories(&mut history, history_size)?;
+                if removed > 0 {
+                    info!(
+                        removed,
+                        history_size, "trimmed the packet history to the new size"
+                    );
                 }
             }
-            let value = packet.as_bytes();
-            table.insert(key.as_bytes(), &value[..])?;
+            metadata.insert(HISTORY_SIZE_KEY, history_size as u64)?;
         }
         tx.commit()?;
-        if replaced {
-            inc!(Metrics, store_packets_updated);
-        } else {
-            inc!(Metrics, store_packets_inserted);
-        }
-        Ok(true)
+        self.history_size = history_size;
+        Ok(self)
+    }
+}
+
+impl PacketStorage for SignedPacketStore {
+    fn upsert(&self, packet: SignedPacket, unmodified_since: Option<u64>) -> Result<UpsertOutcome> {
+        let outcomes = self.upsert_batch(vec![(packet, unmodified_since)])?;
+        Ok(outcomes[0])
+    }
+
+    fn upsert_batch(
+        &self,
+        packets: Vec<(SignedPacket, Option<u64>)>,
+    ) -> Result<Vec<UpsertOutcome>> {
+        let tx = self.db.begin_write()?;
+        let outcomes = {
+            let mut table = tx.open_table(SIGNED_PACKETS_TABLE)?;
+            let mut index = tx.open_table(TIMESTAMP_INDEX_TABLE)?;
+            let mut history = tx.open_table(HISTORY_TABLE)?;
+            let mut updates = tx.open_table(ZONE_UPDATES_TABLE)?;
+            let mut sequences = Sequences::open(&tx)?;
+            let mut outcomes = Vec::with_capacity(packets.len());
+            for (packet, unmodified_since) in packets.iter() {
+                let outcome = upsert_packet(&mut table, &mut index, packet, *unmodified_since)?;
+                if !matches!(outcome, Upserted::Rejected(_)) {
+                    let key = packet.public_key().to_bytes();
+                    sequences.assign(&key)?;
+                    updates.remove(&key)?;
+                    if self.history_size > 0 {
+                        history.insert((&key, *packet.timestamp()), &packet.as_bytes()[..])?;
+                    }
+                    trim_history(&mut history, &key, self.history_size)?;
+                }
+                outcomes.push(outcome);
+            }
+            sequences.save()?;
+            outcomes
+        };
+        tx.commit()?;
+        Ok(outcomes
+            .into_iter()
+            .map(|outcome| match outcome {
+                Upserted::Inserted => {
+                    inc!(Metrics, store_packets_inserted);
+                    UpsertOutcome::Stored
+                }
+                Upserted::Updated => {
+                    inc!(Metrics, store_packets_updated);
+                    UpsertOutcome::Stored
+                }
+                Upserted::Rejected(outcome) => outcome,
+            })
+            .collect())
     }
 
-    pub fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+    fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
         let tx = self.db.begin_read()?;
         let table = tx.open_table(SIGNED_PACKETS_TABLE)?;
         get_packet(&table, key)
     }
 
-    pub fn remove(&self, key: &PublicKeyBytes) -> Result<bool> {
+    fn remove(&self, key: &PublicKeyBytes) -> Result<bool> {
         let tx = self.db.begin_write()?;
         let updated = {
             let mut table = tx.open_table(SIGNED_PACKETS_TABLE)?;
-            let did_remove = table.remove(key.as_bytes())?.is_some();
-            #[allow(clippy::let_and_return)]
-            did_remove
+            let mut index = tx.open_table(TIMESTAMP_INDEX_TABLE)?;
+            let mut history = tx.open_table(HISTORY_TABLE)?;
+            let mut updates = tx.open_table(ZONE_UPDATES_TABLE)?;
+            updates.remove(key.as_bytes())?;
+            Sequences::open(&tx)?.remove(key.as_bytes())?;
+            match get_packet(&table, key)? {
+                Some(existing) => {
+                    table.remove(key.as_bytes())?;
+                    index.remove((*existing.timestamp(), key.as_bytes()))?;
+                    trim_history(&mut history, key.as_bytes(), 0)?;
+                    true
+                }
+                None => false,
+            }
         };
         tx.commit()?;
         if updated {
@@ -84,6 +212,381 @@ impl SignedPacketStore {
         }
         Ok(updated)
     }
+
+    fn iter(&self) -> Result<PacketIter<'_>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(SIGNED_PACKETS_TABLE)?;
+        let iter = table
+            .range::<&SignedPacketsKey>(..)?
+            .map(|row| -> Result<SignedPacket> {
+                let (_key, value) = row?;
+                let packet = SignedPacket::from_bytes(value.value().to_vec().into(), false)?;
+                Ok(packet)
+            });
+        Ok(Box::new(iter))
+    }
+
+    fn remove_older_than(&self, cutoff: u64, limit: usize) -> Result<Vec<PublicKeyBytes>> {
+        let tx = self.db.begin_write()?;
+        let removed = {
+            let mut table = tx.open_table(SIGNED_PACKETS_TABLE)?;
+            let mut index = tx.open_table(TIMESTAMP_INDEX_TABLE)?;
+            let mut history = tx.open_table(HISTORY_TABLE)?;
+            let mut updates = tx.open_table(ZONE_UPDATES_TABLE)?;
+            let mut sequences = Sequences::open(&tx)?;
+            let expired = index_range(&index, ..(cutoff, &MIN_KEY), limit)?;
+            let mut removed = Vec::with_capacity(expired.len());
+            for (timestamp, key) in expired {
+                table.remove(key.as_bytes())?;
+                index.remove((timestamp, key.as_bytes()))?;
+                trim_history(&mut history, key.as_bytes(), 0)?;
+                updates.remove(key.as_bytes())?;
+                sequences.remove(key.as_bytes())?;
+                removed.push(key);
+            }
+            let mut expired_updates = vec![];
+            for row in updates.iter()? {
+                if removed.len() + expired_updates.len() == limit {
+                    break;
+                }
+                let (key, value) = row?;
+                let key = PublicKeyBytes::from(*key.value());
+                if decode_zone_update(key.clone(), value.value())?.timestamp < cutoff {
+                    expired_updates.push(key);
+                }
+            }
+            for key in expired_updates.iter() {
+                updates.remove(key.as_bytes())?;
+            }
+            (removed, expired_updates)
+        };
+        tx.commit()?;
+        let (mut removed, expired_updates) = removed;
+        inc_by!(Metrics, store_packets_removed, removed.len() as u64);
+        removed.extend(expired_updates);
+        Ok(removed)
+    }
+
+    fn updated_since(
+        &self,
+        since: u64,
+        after: Option<&PublicKeyBytes>,
+        limit: usize,
+    ) -> Result<Vec<(u64, PublicKeyBytes)>> {
+        let tx = self.db.begin_read()?;
+        let index = tx.open_table(TIMESTAMP_INDEX_TABLE)?;
+        let start = match after {
+            Some(after) => Bound::Excluded((since, after.as_bytes())),
+            None => Bound::Included((since, &MIN_KEY)),
+        };
+        index_range(&index, (start, Bound::Unbounded), limit)
+    }
+
+    fn get_zone_update(&self, key: &PublicKeyBytes) -> Result<Option<ZoneUpdate>> {
+        let tx = self.db.begin_read()?;
+        let updates = tx.open_table(ZONE_UPDATES_TABLE)?;
+        let Some(row) = updates.get(key.as_bytes())? else {
+            return Ok(None);
+        };
+        let update = decode_zone_update(key.clone(), row.value())?;
+        Ok(Some(update))
+    }
+
+    fn put_zone_update(&self, update: ZoneUpdate, previous: Option<u64>) -> Result<UpsertOutcome> {
+        let tx = self.db.begin_write()?;
+        let outcome = {
+            let table = tx.open_table(SIGNED_PACKETS_TABLE)?;
+            let mut updates = tx.open_table(ZONE_UPDATES_TABLE)?;
+            let key = &update.pubkey;
+            let packet = get_packet(&table, key)?;
+            let current = match updates.get(key.as_bytes())? {
+                Some(row) => Some(decode_zone_update(key.clone(), row.value())?),
+                None => None,
+            };
+            let current = current
+                .filter(|current| current.applies_to(packet.as_ref()))
+                .map(|current| current.timestamp);
+            if !update.applies_to(packet.as_ref()) || current != previous {
+                UpsertOutcome::PreconditionFailed
+            } else {
+                updates.insert(key.as_bytes(), &encode_zone_update(&update)[..])?;
+                let mut sequences = Sequences::open(&tx)?;
+                sequences.assign(key.as_bytes())?;
+                sequences.save()?;
+                UpsertOutcome::Stored
+            }
+        };
+        tx.commit()?;
+        Ok(outcome)
+    }
+
+    fn updated_after(&self, after: u64, limit: usize) -> Result<Vec<(u64, PublicKeyBytes)>> {
+        let tx = self.db.begin_read()?;
+        let index = tx.open_table(SEQUENCE_INDEX_TABLE)?;
+        let mut out = vec![];
+        for row in index
+            .range::<u64>((Bound::Excluded(after), Bound::Unbounded))?
+            .take(limit)
+        {
+            let (sequence, key) = row?;
+            out.push((sequence.value(), PublicKeyBytes::from(*key.value())));
+        }
+        Ok(out)
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<u64>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(METADATA_TABLE)?;
+        let value = table.get(key)?.map(|value| value.value());
+        Ok(value)
+    }
+
+    fn set_metadata(&self, key: &str, value: u64) -> Result<()> {
+        ensure!(
+            ![SCHEMA_VERSION_KEY, LAST_SEQUENCE_KEY, HISTORY_SIZE_KEY].contains(&key),
+            "{key} is managed by the store"
+        );
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(METADATA_TABLE)?;
+            table.insert(key, value)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn history(&self, key: &PublicKeyBytes) -> Result<Vec<SignedPacket>> {
+        if self.history_size == 0 {
+            return Ok(self.get(key)?.into_iter().collect());
+        }
+        let tx = self.db.begin_read()?;
+        let history = tx.open_table(HISTORY_TABLE)?;
+        let mut packets = vec![];
+        let range = history.range((key.as_bytes(), 0)..=(key.as_bytes(), u64::MAX))?;
+        // Only serve the configured size, even if the history wasn't trimmed yet.
+        for row in range.rev().take(self.history_size) {
+            let (_key, value) = row?;
+            let packet = SignedPacket::from_bytes(value.value().to_vec().into(), false)?;
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+}
+
+/// Upgrade the database to [`SCHEMA_VERSION`].
+///
+/// Fails if the database was written by a newer version of the server.
+fn migrate(tx: &WriteTransaction) -> Result<()> {
+    let version = schema_version(tx)?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "database schema version {version} is newer than the latest supported version \
+            {SCHEMA_VERSION}, refusing to open it with this version of iroh-dns-server"
+        );
+    }
+    if version < SCHEMA_VERSION {
+        info!("migrating database schema from version {version} to {SCHEMA_VERSION}");
+    }
+    if version < 1 {
+        // Fresh database.
+        tx.open_table(SIGNED_PACKETS_TABLE)?;
+    }
+    if version < 2 {
+        migrate_v1_to_v2(tx)?;
+    }
+    let mut metadata = tx.open_table(METADATA_TABLE)?;
+    metadata.insert(SCHEMA_VERSION_KEY, SCHEMA_VERSION)?;
+    Ok(())
+}
+
+/// Read the schema version of the database.
+///
+/// Returns 0 for a new, empty database. Databases created before the metadata table
+/// existed only contain the signed packets table and are at version 1.
+fn schema_version(tx: &WriteTransaction) -> Result<u64> {
+    let mut has_metadata = false;
+    let mut has_packets = false;
+    for table in tx.list_tables()? {
+        has_metadata |= table.name() == METADATA_TABLE.name();
+        has_packets |= table.name() == SIGNED_PACKETS_TABLE.name();
+    }
+    if has_metadata {
+        let metadata = tx.open_table(METADATA_TABLE)?;
+        let version = metadata.get(SCHEMA_VERSION_KEY)?.map(|v| v.value());
+        if let Some(version) = version {
+            return Ok(version);
+        }
+    }
+    Ok(if has_packets { 1 } else { 0 })
+}
+
+/// Version 2 adds the [`TIMESTAMP_INDEX_TABLE`], the [`HISTORY_TABLE`], the [`SEQUENCE_TABLE`]
+/// with its index and the [`ZONE_UPDATES_TABLE`].
+///
+/// The index and the history are populated from the existing packets, and sequence numbers are
+/// assigned to them in the order of their pkarr timestamps.
+fn migrate_v1_to_v2(tx: &WriteTransaction) -> Result<()> {
+    let packets = tx.open_table(SIGNED_PACKETS_TABLE)?;
+    let mut index = tx.open_table(TIMESTAMP_INDEX_TABLE)?;
+    let mut history = tx.open_table(HISTORY_TABLE)?;
+    tx.open_table(ZONE_UPDATES_TABLE)?;
+    let mut entries = vec![];
+    for row in packets.iter()? {
+        let (key, value) = row?;
+        let key = *key.value();
+        match SignedPacket::from_bytes(value.value().to_vec().into(), false) {
+            Ok(packet) => {
+                index.insert((*packet.timestamp(), &key), ())?;
+                history.insert((&key, *packet.timestamp()), value.value())?;
+                entries.push((*packet.timestamp(), key));
+            }
+            Err(err) => {
+                let key = PublicKeyBytes::from(key);
+                warn!(%key, ?err, "skipping invalid packet in store");
+            }
+        }
+    }
+    entries.sort();
+    let mut sequences = Sequences::open(tx)?;
+    for (_timestamp, key) in entries.iter() {
+        sequences.assign(key)?;
+    }
+    sequences.save()
+}
+
+/// The sequence number tables within a write transaction, see [`SEQUENCE_TABLE`].
+struct Sequences<'a> {
+    by_key: Table<'a, &'static SignedPacketsKey, u64>,
+    by_sequence: Table<'a, u64, &'static SignedPacketsKey>,
+    metadata: Table<'a, &'static str, u64>,
+    last: u64,
+}
+
+impl<'a> Sequences<'a> {
+    fn open(tx: &'a WriteTransaction) -> Result<Self> {
+        let metadata = tx.open_table(METADATA_TABLE)?;
+        let last = metadata.get(LAST_SEQUENCE_KEY)?.map_or(0, |v| v.value());
+        Ok(Self {
+            by_key: tx.open_table(SEQUENCE_TABLE)?,
+            by_sequence: tx.open_table(SEQUENCE_INDEX_TABLE)?,
+            metadata,
+            last,
+        })
+    }
+
+    /// Assign the next sequence number to a public key.
+    fn assign(&mut self, key: &SignedPacketsKey) -> Result<()> {
+        self.remove(key)?;
+        self.last += 1;
+        self.by_key.insert(key, self.last)?;
+        self.by_sequence.insert(self.last, key)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &SignedPacketsKey) -> Result<()> {
+        if let Some(sequence) = self.by_key.remove(key)? {
+            self.by_sequence.remove(sequence.value())?;
+        }
+        Ok(())
+    }
+
+    /// Persist the last assigned sequence number, so that sequence numbers are never reused.
+    fn save(mut self) -> Result<()> {
+        self.metadata.insert(LAST_SEQUENCE_KEY, self.last)?;
+        Ok(())
+    }
+}
+
+fn encode_zone_update(update: &ZoneUpdate) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(16 + update.encoded_packet.len());
+    bytes.extend_from_slice(&update.base_timestamp.to_be_bytes());
+    bytes.extend_from_slice(&update.timestamp.to_be_bytes());
+    bytes.extend_from_slice(&update.encoded_packet);
+    bytes
+}
+
+fn decode_zone_update(pubkey: PublicKeyBytes, bytes: &[u8]) -> Result<ZoneUpdate> {
+    ensure!(bytes.len() >= 16, "invalid zone update");
+    let base_timestamp = u64::from_be_bytes(bytes[..8].try_into().expect("length checked"));
+    let timestamp = u64::from_be_bytes(bytes[8..16].try_into().expect("length checked"));
+    Ok(ZoneUpdate {
+        pubkey,
+        base_timestamp,
+        timestamp,
+        encoded_packet: bytes[16..].to_vec().into(),
+    })
+}
+
+/// Outcome of [`upsert_packet`].
+enum Upserted {
+    Inserted,
+    Updated,
+    Rejected(UpsertOutcome),
+}
+
+/// Store a packet within a write transaction, unless [`UpsertOutcome::check`] rejects it.
+fn upsert_packet(
+    table: &mut Table<'_, &'static SignedPacketsKey, &'static [u8]>,
+    index: &mut Table<'_, TimestampIndexKey<'static>, ()>,
+    packet: &SignedPacket,
+    unmodified_since: Option<u64>,
+) -> Result<Upserted> {
+    let key = PublicKeyBytes::from_signed_packet(packet);
+    let mut outcome = Upserted::Inserted;
+    if let Some(existing) = get_packet(&*table, &key)? {
+        if let Some(rejected) = UpsertOutcome::check(&existing, packet, unmodified_since) {
+            return Ok(Upserted::Rejected(rejected));
+        }
+        outcome = Upserted::Updated;
+        index.remove((*existing.timestamp(), key.as_bytes()))?;
+    }
+    let value = packet.as_bytes();
+    table.insert(key.as_bytes(), &value[..])?;
+    index.insert((*packet.timestamp(), key.as_bytes()), ())?;
+    Ok(outcome)
+}
+
+/// Remove all but the `size` most recent history entries of a public key.
+fn trim_history(
+    history: &mut Table<'_, HistoryKey<'static>, &'static [u8]>,
+    key: &SignedPacketsKey,
+    size: usize,
+) -> Result<()> {
+    let mut timestamps = vec![];
+    for row in history.range((key, 0)..=(key, u64::MAX))? {
+        let (row_key, _) = row?;
+        timestamps.push(row_key.value().1);
+    }
+    let excess = timestamps.len().saturating_sub(size);
+    for timestamp in &timestamps[..excess] {
+        history.remove((key, *timestamp))?;
+    }
+    Ok(())
+}
+
+/// Remove all but the `size` most recent history entries of every public key.
+///
+/// Returns the number of removed entries.
+fn trim_all_histories(
+    history: &mut Table<'_, HistoryKey<'static>, &'static [u8]>,
+    size: usize,
+) -> Result<usize> {
+    let mut entries = vec![];
+    for row in history.iter()? {
+        let (row_key, _) = row?;
+        let (key, timestamp) = row_key.value();
+        entries.push((*key, timestamp));
+    }
+    // Entries are ordered by public key and timestamp, so each group starts with the oldest.
+    let mut excess = vec![];
+    for group in entries.chunk_by(|a, b| a.0 == b.0) {
+        let count = group.len().saturating_sub(size);
+        excess.extend_from_slice(&group[..count]);
+    }
+    for (key, timestamp) in excess.iter() {
+        history.remove((key, *timestamp))?;
+    }
+    Ok(excess.len())
 }
 
 fn get_packet(
@@ -96,3 +599,217 @@ fn get_packet(
     let packet = SignedPacket::from_bytes(row.value().to_vec().into(), false)?;
     Ok(Some(packet))
 }
+
+fn index_range<'a>(
+    index: &impl ReadableTable<TimestampIndexKey<'static>, ()>,
+    range: impl std::ops::RangeBounds<TimestampIndexKey<'a>> + 'a,
+    limit: usize,
+) -> Result<Vec<(u64, PublicKeyBytes)>> {
+    let mut out = vec![];
+    for row in index.range(range)?.take(limit) {
+        let (key, _) = row?;
+        let (timestamp, pubkey) = key.value();
+        out.push((timestamp, PublicKeyBytes::from(*pubkey)));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{node_packet, random_node_packet};
+
+    fn in_memory_db() -> Result<Database> {
+        Ok(Database::builder().create_with_backend(InMemoryBackend::new())?)
+    }
+
+    #[test]
+    fn migrate_from_v1() -> Result<()> {
+        let (_secret_key, packet) = random_node_packet()?;
+        let key = PublicKeyBytes::from_signed_packet(&packet);
+        let db = in_memory_db()?;
+        let tx = db.begin_write()?;
+        {
+            let mut table = tx.open_table(SIGNED_PACKETS_TABLE)?;
+            table.insert(key.as_bytes(), &packet.as_bytes()[..])?;
+        }
+        tx.commit()?;
+
+        let store = SignedPacketStore::open(db)?;
+        let tx = store.db.begin_write()?;
+        assert_eq!(schema_version(&tx)?, SCHEMA_VERSION);
+        drop(tx);
+
+        // The existing packet is added to the timestamp index, the sequence index and the history.
+        assert_eq!(
+            store.updated_since(0, None, usize::MAX)?,
+            vec![(*packet.timestamp(), key.clone())]
+        );
+        assert_eq!(store.updated_after(0, usize::MAX)?, vec![(1, key.clone())]);
+        let history = store.history(&key)?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].as_bytes(), packet.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_newer_schema() -> Result<()> {
+        let db = in_memory_db()?;
+        let tx = db.begin_write()?;
+        {
+            let mut metadata = tx.open_table(METADATA_TABLE)?;
+            metadata.insert(SCHEMA_VERSION_KEY, SCHEMA_VERSION + 1)?;
+        }
+        tx.commit()?;
+
+        assert!(SignedPacketStore::open(db).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn timestamp_index_in_sync() -> Result<()> {
+        let store = SignedPacketStore::in_memory()?;
+        let indexed = || store.updated_since(0, None, usize::MAX);
+
+        let (secret_key, packet) = random_node_packet()?;
+        let key = PublicKeyBytes::from_signed_packet(&packet);
+        store.upsert(packet, None)?;
+        let first = store.get(&key)?.expect("packet is stored");
+        assert_eq!(indexed()?, vec![(*first.timestamp(), key.clone())]);
+
+        // Replacing the packet moves its index entry to the new timestamp.
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let packet = node_packet(&secret_key)?;
+        let timestamp = *packet.timestamp();
+        assert!(timestamp > *first.timestamp());
+        assert_eq!(store.upsert(packet, None)?, UpsertOutcome::Stored);
+        assert_eq!(indexed()?, vec![(timestamp, key.clone())]);
+
+        // A rejected outdated packet leaves the index untouched.
+        assert_eq!(store.upsert(first, None)?, UpsertOutcome::Outdated);
+        assert_eq!(indexed()?, vec![(timestamp, key.clone())]);
+
+        assert!(store.remove(&key)?);
+        assert!(indexed()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn sequence_index_in_sync() -> Result<()> {
+        let store = SignedPacketStore::in_memory()?;
+        let sequences = || store.updated_after(0, usize::MAX);
+
+        let (secret_key, first) = random_node_packet()?;
+        let (_, second) = random_node_packet()?;
+        let key_first = PublicKeyBytes::from_signed_packet(&first);
+        let key_second = PublicKeyBytes::from_signed_packet(&second);
+        store.upsert(first, None)?;
+        store.upsert(second, None)?;
+        assert_eq!(
+            sequences()?,
+            vec![(1, key_first.clone()), (2, key_second.clone())]
+        );
+
+        // Replacing a packet moves it to the end, regardless of its timestamp.
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        store.upsert(node_packet(&secret_key)?, None)?;
+        assert_eq!(
+            sequences()?,
+            vec![(2, key_second.clone()), (3, key_first.clone())]
+        );
+        assert_eq!(
+            store.updated_after(2, usize::MAX)?,
+            vec![(3, key_first.clone())]
+        );
+
+        // Sequence numbers are not reused after removals.
+        assert!(store.remove(&key_first)?);
+        assert_eq!(sequences()?, vec![(2, key_second.clone())]);
+        let (_, third) = random_node_packet()?;
+        let key_third = PublicKeyBytes::from_signed_packet(&third);
+        store.upsert(third, None)?;
+        assert_eq!(sequences()?, vec![(2, key_second), (4, key_third)]);
+        Ok(())
+    }
+
+    #[test]
+    fn history_is_trimmed() -> Result<()> {
+        let store = SignedPacketStore::in_memory()?.with_history_size(2)?;
+        let (secret_key, first) = random_node_packet()?;
+        let key = PublicKeyBytes::from_signed_packet(&first);
+        let mut packets = vec![first];
+        for _ in 0..2 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            packets.push(node_packet(&secret_key)?);
+        }
+        let timestamps: Vec<u64> = packets.iter().map(|p| *p.timestamp()).collect();
+        for packet in packets {
+            store.upsert(packet, None)?;
+        }
+
+        // Only the two most recent packets are kept, newest first.
+        let history = store.history(&key)?;
+        let history: Vec<u64> = history.iter().map(|p| *p.timestamp()).collect();
+        assert_eq!(history, vec![timestamps[2], timestamps[1]]);
+
+        assert!(store.remove(&key)?);
+        assert!(store.history(&key)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn history_size_lowered() -> Result<()> {
+        let mut store = SignedPacketStore::in_memory()?.with_history_size(3)?;
+        let (secret_key, first) = random_node_packet()?;
+        let key = PublicKeyBytes::from_signed_packet(&first);
+        let mut packets = vec![first];
+        for _ in 0..2 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            packets.push(node_packet(&secret_key)?);
+        }
+        let timestamps: Vec<u64> = packets.iter().map(|p| *p.timestamp()).collect();
+        for packet in packets {
+            store.upsert(packet, None)?;
+        }
+        let stored_history = |store: &SignedPacketStore| -> Result<usize> {
+            let tx = store.db.begin_read()?;
+            let history = tx.open_table(HISTORY_TABLE)?;
+            Ok(history.iter()?.count())
+        };
+
+        // Reads are limited to the configured size, even before the history is trimmed.
+        store.history_size = 2;
+        let history = store.history(&key)?;
+        let history: Vec<u64> = history.iter().map(|p| *p.timestamp()).collect();
+        assert_eq!(history, vec![timestamps[2], timestamps[1]]);
+        assert_eq!(stored_history(&store)?, 3);
+
+        // Lowering the configured size trims the existing histories.
+        let store = store.with_history_size(1)?;
+        assert_eq!(stored_history(&store)?, 1);
+        let store = store.with_history_size(0)?;
+        assert_eq!(stored_history(&store)?, 0);
+        let history = store.history(&key)?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(*history[0].timestamp(), timestamps[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn history_disabled() -> Result<()> {
+        let store = SignedPacketStore::in_memory()?;
+        let (secret_key, first) = random_node_packet()?;
+        let key = PublicKeyBytes::from_signed_packet(&first);
+        store.upsert(first, None)?;
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let second = node_packet(&secret_key)?;
+        let timestamp = *second.timestamp();
+        store.upsert(second, None)?;
+
+        // Without a history, only the current packet is returned.
+        let history = store.history(&key)?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(*history[0].timestamp(), timestamp);
+        Ok(())
+    }
+}
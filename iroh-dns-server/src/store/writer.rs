@@ -0,0 +1,446 @@
+//! A dedicated thread for writes to the [`PacketStorage`].
+//!
+//! Writes to the storage may block for a long time, e.g. on fsync. They are sent over a channel
+//! to a single writer thread, so that they never block the async runtime.
+//!
+//! Concurrent upserts are grouped into batches, which are committed in a single transaction with
+//! [`PacketStorage::upsert_batch`]. A batch takes the upserts that queue up while the writer is
+//! busy, and those that arrive within the configured batch time.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use iroh_metrics::inc;
+use pkarr::SignedPacket;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+use tracing::{debug, trace, warn};
+
+use super::{PacketStorage, UpsertOutcome, ZoneStoreOptions, ZoneUpdate};
+use crate::{metrics::Metrics, util::PublicKeyBytes};
+
+/// Capacity of the channel to the writer thread.
+const CHANNEL_CAPACITY: usize = 1024;
+
+enum Message {
+    Upsert {
+        packet: SignedPacket,
+        unmodified_since: Option<u64>,
+        reply: oneshot::Sender<Result<UpsertOutcome>>,
+    },
+    Remove {
+        key: PublicKeyBytes,
+        reply: oneshot::Sender<Result<bool>>,
+    },
+    RemoveOlderThan {
+        cutoff: u64,
+        limit: usize,
+        reply: oneshot::Sender<Result<Vec<PublicKeyBytes>>>,
+    },
+    PutZoneUpdate {
+        update: ZoneUpdate,
+        previous: Option<u64>,
+        reply: oneshot::Sender<Result<UpsertOutcome>>,
+    },
+    SetMetadata {
+        key: String,
+        value: u64,
+        reply: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// Handle to the writer thread.
+///
+/// The thread stops once all handles are dropped.
+#[derive(Debug, Clone)]
+pub struct StoreWriter {
+    tx: mpsc::Sender<Message>,
+}
+
+impl StoreWriter {
+    /// Spawn the writer thread.
+    pub fn spawn(store: Arc<dyn PacketStorage>, options: &ZoneStoreOptions) -> Result<Self> {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        // The thread runs its own single-threaded runtime to wait for batches with a timeout.
+        // Storage calls block this runtime, which is fine because nothing else runs on it.
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()?;
+        let actor = Actor {
+            store,
+            rx,
+            max_batch_size: options.max_batch_size.max(1),
+            max_batch_time: options.max_batch_time,
+        };
+        std::thread::Builder::new()
+            .name("iroh-dns-store-writer".to_string())
+            .spawn(move || rt.block_on(actor.run()))?;
+        Ok(Self { tx })
+    }
+
+    /// Store a packet, see [`PacketStorage::upsert`].
+    pub async fn upsert(
+        &self,
+        packet: SignedPacket,
+        unmodified_since: Option<u64>,
+    ) -> Result<UpsertOutcome> {
+        self.request(|reply| Message::Upsert {
+            packet,
+            unmodified_since,
+            reply,
+        })
+        .await
+    }
+
+    /// Remove a packet, see [`PacketStorage::remove`].
+    pub async fn remove(&self, key: PublicKeyBytes) -> Result<bool> {
+        self.request(|reply| Message::Remove { key, reply }).await
+    }
+
+    /// Remove old packets, see [`PacketStorage::remove_older_than`].
+    pub async fn remove_older_than(
+        &self,
+        cutoff: u64,
+        limit: usize,
+    ) -> Result<Vec<PublicKeyBytes>> {
+        self.request(|reply| Message::RemoveOlderThan {
+            cutoff,
+            limit,
+            reply,
+        })
+        .await
+    }
+
+    /// Store a zone update, see [`PacketStorage::put_zone_update`].
+    pub async fn put_zone_update(
+        &self,
+        update: ZoneUpdate,
+        previous: Option<u64>,
+    ) -> Result<UpsertOutcome> {
+        self.request(|reply| Message::PutZoneUpdate {
+            update,
+            previous,
+            reply,
+        })
+        .await
+    }
+
+    /// Set a metadata value, see [`PacketStorage::set_metadata`].
+    pub async fn set_metadata(&self, key: String, value: u64) -> Result<()> {
+        self.request(|reply| Message::SetMetadata { key, value, reply })
+            .await
+    }
+
+    async fn request<T>(&self, f: impl FnOnce(oneshot::Sender<Result<T>>) -> Message) -> Result<T> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.tx
+            .send(f(reply))
+            .await
+            .map_err(|_| anyhow!("store writer stopped"))?;
+        reply_rx
+            .await
+            .map_err(|_| anyhow!("store writer dropped the request"))?
+    }
+}
+
+type UpsertRequest = (
+    (SignedPacket, Option<u64>),
+    oneshot::Sender<Result<UpsertOutcome>>,
+);
+
+struct Actor {
+    store: Arc<dyn PacketStorage>,
+    rx: mpsc::Receiver<Message>,
+    max_batch_size: usize,
+    max_batch_time: Duration,
+}
+
+impl Actor {
+    async fn run(mut self) {
+        // A message received while collecting a batch, to be handled after the batch.
+        let mut next = None;
+        loop {
+            let msg = match next.take() {
+                Some(msg) => msg,
+                None => match self.rx.recv().await {
+                    Some(msg) => msg,
+                    None => break,
+                },
+            };
+            // A dropped reply receiver only means that the caller is no longer interested.
+            match msg {
+                Message::Upsert {
+                    packet,
+                    unmodified_since,
+                    reply,
+                } => {
+                    let request = ((packet, unmodified_since), reply);
+                    let (batch, pending) = self.collect_batch(request).await;
+                    next = pending;
+                    self.upsert_batch(batch);
+                }
+                Message::Remove { key, reply } => {
+                    reply.send(self.store.remove(&key)).ok();
+                }
+                Message::RemoveOlderThan {
+                    cutoff,
+                    limit,
+                    reply,
+                } => {
+                    reply.send(self.store.remove_older_than(cutoff, limit)).ok();
+                }
+                Message::PutZoneUpdate {
+                    update,
+                    previous,
+                    reply,
+                } => {
+                    reply
+                        .send(self.store.put_zone_update(update, previous))
+                        .ok();
+                }
+                Message::SetMetadata { key, value, reply } => {
+                    reply.send(self.store.set_metadata(&key, value)).ok();
+                }
+            }
+        }
+        debug!("store writer stopped");
+    }
+
+    /// Collect upserts until the batch is full or the batch time has elapsed.
+    ///
+    /// Upserts that are already queued are always taken, even after the batch time.
+    /// Returns the batch and the message that ended the batch, if it was not an upsert.
+    async fn collect_batch(
+        &mut self,
+        first: UpsertRequest,
+    ) -> (Vec<UpsertRequest>, Option<Message>) {
+        let deadline = Instant::now() + self.max_batch_time;
+        let mut batch = vec![first];
+        while batch.len() < self.max_batch_size {
+            // The timeout polls the receiver before checking the deadline.
+            match tokio::time::timeout_at(deadline, self.rx.recv()).await {
+                Ok(Some(Message::Upsert {
+                    packet,
+                    unmodified_since,
+                    reply,
+                })) => batch.push(((packet, unmodified_since), reply)),
+                Ok(Some(msg)) => return (batch, Some(msg)),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        (batch, None)
+    }
+
+    /// Commit a batch of upserts.
+    ///
+    /// If the batch fails, its upserts are retried one by one, so that a single bad packet only
+    /// fails its own upsert.
+    fn upsert_batch(&self, batch: Vec<UpsertRequest>) {
+        trace!(len = batch.len(), "store writer: commit batch");
+        inc!(Metrics, store_write_batches);
+        let (packets, replies): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        // Packets are consumed by the batch, keep their cheaply cloned bytes for the retries.
+        let retries: Vec<(Bytes, Option<u64>)> = match packets.len() {
+            1 => vec![],
+            _ => packets
+                .iter()
+                .map(|(packet, unmodified_since)| (packet.as_bytes().clone(), *unmodified_since))
+                .collect(),
+        };
+        match self.store.upsert_batch(packets) {
+            Ok(outcomes) => {
+                for (reply, outcome) in replies.into_iter().zip(outcomes) {
+                    reply.send(Ok(outcome)).ok();
+                }
+            }
+            Err(err) if retries.is_empty() => {
+                for reply in replies {
+                    reply.send(Err(anyhow!("{err:#}"))).ok();
+                }
+            }
+            Err(err) => {
+                warn!(
+                    len = replies.len(),
+                    ?err,
+                    "store writer: batch failed, retrying each upsert"
+                );
+                for (reply, (bytes, unmodified_since)) in replies.into_iter().zip(retries) {
+                    let outcome = SignedPacket::from_bytes(bytes, false)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|packet| self.store.upsert(packet, unmodified_since));
+                    reply.send(outcome).ok();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{bail, Result};
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::{
+        store::{InMemoryPacketStore, PacketIter},
+        test_utils::random_node_packet,
+    };
+
+    /// Records the size of each batch, and fails upserts of the `poisoned` key.
+    #[derive(Debug, Default)]
+    struct TestStorage {
+        inner: InMemoryPacketStore,
+        batches: Mutex<Vec<usize>>,
+        poisoned: Option<PublicKeyBytes>,
+    }
+
+    impl TestStorage {
+        fn check(&self, packet: &SignedPacket) -> Result<()> {
+            if Some(PublicKeyBytes::from_signed_packet(packet)) == self.poisoned {
+                bail!("poisoned");
+            }
+            Ok(())
+        }
+    }
+
+    impl PacketStorage for TestStorage {
+        fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+            self.inner.get(key)
+        }
+
+        fn upsert(
+            &self,
+            packet: SignedPacket,
+            unmodified_since: Option<u64>,
+        ) -> Result<UpsertOutcome> {
+            self.check(&packet)?;
+            self.inner.upsert(packet, unmodified_since)
+        }
+
+        fn upsert_batch(
+            &self,
+            packets: Vec<(SignedPacket, Option<u64>)>,
+        ) -> Result<Vec<UpsertOutcome>> {
+            self.batches.lock().push(packets.len());
+            // A slow commit, so that concurrent upserts queue up.
+            std::thread::sleep(Duration::from_millis(10));
+            for (packet, _) in &packets {
+                self.check(packet)?;
+            }
+            self.inner.upsert_batch(packets)
+        }
+
+        fn remove(&self, key: &PublicKeyBytes) -> Result<bool> {
+            self.inner.remove(key)
+        }
+
+        fn iter(&self) -> Result<PacketIter<'_>> {
+            self.inner.iter()
+        }
+
+        fn updated_after(&self, after: u64, limit: usize) -> Result<Vec<(u64, PublicKeyBytes)>> {
+            self.inner.updated_after(after, limit)
+        }
+
+        fn get_zone_update(&self, key: &PublicKeyBytes) -> Result<Option<ZoneUpdate>> {
+            self.inner.get_zone_update(key)
+        }
+
+        fn put_zone_update(
+            &self,
+            update: ZoneUpdate,
+            previous: Option<u64>,
+        ) -> Result<UpsertOutcome> {
+            self.inner.put_zone_update(update, previous)
+        }
+
+        fn metadata(&self, key: &str) -> Result<Option<u64>> {
+            self.inner.metadata(key)
+        }
+
+        fn set_metadata(&self, key: &str, value: u64) -> Result<()> {
+            self.inner.set_metadata(key, value)
+        }
+    }
+
+    fn spawn_writer(storage: &Arc<TestStorage>) -> Result<StoreWriter> {
+        StoreWriter::spawn(storage.clone(), &Default::default())
+    }
+
+    #[tokio::test]
+    async fn batch_queued_upserts() -> Result<()> {
+        let storage = Arc::new(TestStorage::default());
+        let writer = spawn_writer(&storage)?;
+
+        // A single upsert is committed on its own.
+        let (_secret_key, packet) = random_node_packet()?;
+        assert_eq!(writer.upsert(packet, None).await?, UpsertOutcome::Stored);
+        assert_eq!(*storage.batches.lock(), vec![1]);
+
+        // Upserts sent during a commit are committed together.
+        let upserts = (0..64).map(|_| {
+            let (_secret_key, packet) = random_node_packet().unwrap();
+            writer.upsert(packet, None)
+        });
+        let outcomes = futures::future::try_join_all(upserts).await?;
+        assert!(outcomes.iter().all(|o| *o == UpsertOutcome::Stored));
+        let batches = storage.batches.lock().clone();
+        assert_eq!(batches.iter().sum::<usize>(), 65);
+        assert!(batches.len() < 10, "batches: {batches:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batch_upserts_within_batch_time() -> Result<()> {
+        let storage = Arc::new(TestStorage::default());
+        let options = ZoneStoreOptions {
+            max_batch_time: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let writer = StoreWriter::spawn(storage.clone(), &options)?;
+
+        // An upsert arriving within the batch time of an idle writer joins the batch.
+        let (_secret_key, first) = random_node_packet()?;
+        let (_secret_key, second) = random_node_packet()?;
+        let first = tokio::task::spawn({
+            let writer = writer.clone();
+            async move { writer.upsert(first, None).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(writer.upsert(second, None).await?, UpsertOutcome::Stored);
+        assert_eq!(first.await??, UpsertOutcome::Stored);
+        assert_eq!(*storage.batches.lock(), vec![2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn isolate_failed_upsert() -> Result<()> {
+        let packets = (0..8)
+            .map(|_| Ok(random_node_packet()?.1))
+            .collect::<Result<Vec<_>>>()?;
+        let poisoned = PublicKeyBytes::from_signed_packet(&packets[4]);
+        let storage = Arc::new(TestStorage {
+            poisoned: Some(poisoned.clone()),
+            ..Default::default()
+        });
+        let writer = spawn_writer(&storage)?;
+
+        let upserts = packets
+            .into_iter()
+            .map(|packet| writer.upsert(packet, None));
+        let outcomes = futures::future::join_all(upserts).await;
+        for (i, outcome) in outcomes.into_iter().enumerate() {
+            match i {
+                4 => assert!(outcome.is_err()),
+                _ => assert_eq!(outcome?, UpsertOutcome::Stored),
+            }
+        }
+        assert!(storage.inner.get(&poisoned)?.is_none());
+        // The failing upsert was part of a batch.
+        assert!(storage.batches.lock().iter().any(|len| *len > 1));
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+//! Helpers for tests.
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use iroh_metrics::core::{Core, Metric};
+use iroh_net::{
+    discovery::pkarr_publish::PkarrRelayClient, dns::node_info::NodeInfo, key::SecretKey,
+};
+use pkarr::SignedPacket;
+use url::Url;
+
+use crate::metrics::Metrics;
+
+/// The relay URL announced in the packets created by [`node_packet`].
+pub const RELAY_URL: &str = "https://relay.example.";
+
+/// Create a signed packet announcing the node of `secret_key` with [`RELAY_URL`].
+pub fn node_packet(secret_key: &SecretKey) -> Result<SignedPacket> {
+    node_packet_with_ttl(secret_key, 30)
+}
+
+/// Create a signed packet like [`node_packet`], with a TTL of `ttl` seconds.
+pub fn node_packet_with_ttl(secret_key: &SecretKey, ttl: u32) -> Result<SignedPacket> {
+    let node_info = NodeInfo::new(secret_key.public(), Some(RELAY_URL.parse()?));
+    node_info.to_pkarr_signed_packet(secret_key, ttl)
+}
+
+/// Create a signed packet like [`node_packet`], with the pkarr timestamp `timestamp`.
+///
+/// The timestamp is in microseconds since the unix epoch.
+pub fn node_packet_with_timestamp(secret_key: &SecretKey, timestamp: u64) -> Result<SignedPacket> {
+    let encoded_packet = node_packet(secret_key)?.encoded_packet();
+    let mut signable = format!("3:seqi{timestamp}e1:v{}:", encoded_packet.len()).into_bytes();
+    signable.extend(&encoded_packet);
+    let mut bytes = secret_key.public().as_bytes().to_vec();
+    bytes.extend(secret_key.sign(&signable).to_bytes());
+    bytes.extend(timestamp.to_be_bytes());
+    bytes.extend(&encoded_packet);
+    Ok(SignedPacket::from_bytes(bytes.into(), true)?)
+}
+
+/// Create a signed packet announcing a new random node.
+pub fn random_node_packet() -> Result<(SecretKey, SignedPacket)> {
+    let secret_key = SecretKey::generate();
+    let packet = node_packet(&secret_key)?;
+    Ok((secret_key, packet))
+}
+
+/// Publish a signed packet announcing a new random node to the pkarr relay at `relay`.
+pub async fn publish_random_node_packet(relay: Url) -> Result<(SecretKey, SignedPacket)> {
+    let (secret_key, packet) = random_node_packet()?;
+    PkarrRelayClient::new(relay).publish(&packet).await?;
+    Ok((secret_key, packet))
+}
+
+/// Copy a signed packet, as [`SignedPacket`] doesn't implement `Clone`.
+pub fn copy_packet(packet: &SignedPacket) -> SignedPacket {
+    SignedPacket::from_bytes(packet.as_bytes().clone(), false).expect("packet is valid")
+}
+
+/// Flip a bit in the signature of a packet, so that it no longer verifies.
+pub fn forge_packet(packet: &SignedPacket) -> Result<SignedPacket> {
+    let mut bytes = packet.as_bytes().to_vec();
+    bytes[32] ^= 1;
+    Ok(SignedPacket::from_bytes(bytes.into(), false)?)
+}
+
+/// Get the global [`Metrics`], initializing the metrics collection if needed.
+///
+/// The metrics are shared by all tests, so counters should only be checked for increments.
+pub fn metrics() -> &'static Metrics {
+    Core::try_init(|reg, metrics| {
+        metrics.insert(Metrics::new(reg));
+    })
+    .ok();
+    Core::get()
+        .and_then(|core| core.get_collector::<Metrics>())
+        .expect("metrics are initialized")
+}
+
+/// A unique path in the temp directory, whose file is removed on drop.
+///
+/// The file is removed even if the test fails before its end.
+#[derive(Debug)]
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// Create a new path with the file extension `extension`.
+    pub fn new(extension: &str) -> Self {
+        let name = format!(
+            "iroh-dns-server-test-{}.{extension}",
+            SecretKey::generate().public()
+        );
+        Self(std::env::temp_dir().join(name))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        // The file might not have been created.
+        std::fs::remove_file(&self.0).ok();
+    }
+}
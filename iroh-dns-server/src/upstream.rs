@@ -0,0 +1,227 @@
+//! Resolving signed packets from and publishing them to upstream sources, e.g. the mainline DHT.
+//!
+//! The [`crate::store::ZoneStore`] consults an [`UpstreamResolver`] when a pubkey is not found
+//! in the local store, and stores the result like any other published packet.
+//!
+//! The republisher task spawned with [`crate::store::ZoneStore::spawn_republisher`] pushes the
+//! stored packets to an [`UpstreamPublisher`], so that they stay resolvable upstream.
+//!
+//! The [`RelayForwarder`] forwards packets published to this server to other pkarr relays. A
+//! [`RelayClient`] can also be used as an upstream resolver, to proxy lookups that miss locally
+//! to another pkarr relay.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use pkarr::SignedPacket;
+
+use crate::{store::PacketSource, util::PublicKeyBytes};
+
+pub use self::{
+    forward::{
+        ForwardOptions, RelayForwarder, DEFAULT_FORWARD_INITIAL_BACKOFF,
+        DEFAULT_FORWARD_MAX_RETRIES,
+    },
+    mainline::MainlineClient,
+    mock::MockDht,
+    relay::RelayClient,
+};
+
+mod forward;
+mod mainline;
+mod mock;
+mod relay;
+
+/// An upstream source of signed packets.
+#[async_trait]
+pub trait UpstreamResolver: std::fmt::Debug + Send + Sync + 'static {
+    /// Resolve the most recent signed packet for a pubkey.
+    ///
+    /// Implementations must only return packets with a valid signature.
+    async fn resolve(&self, pubkey: &PublicKeyBytes) -> Result<Option<SignedPacket>>;
+
+    /// The source recorded for packets resolved from this upstream.
+    fn source(&self) -> PacketSource {
+        PacketSource::Upstream
+    }
+}
+
+/// An upstream destination for signed packets.
+#[async_trait]
+pub trait UpstreamPublisher: std::fmt::Debug + Send + Sync + 'static {
+    /// Publish a signed packet.
+    async fn publish(&self, packet: &SignedPacket) -> Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use hickory_proto::rr::{Name, RecordType};
+    use iroh_net::{dns::node_info::IROH_TXT_NAME, key::SecretKey};
+    use pkarr::SignedPacket;
+
+    use super::{MockDht, UpstreamResolver};
+    use crate::{
+        store::{PacketSource, ZoneStore, MAX_CONCURRENT_UPSTREAM_LOOKUPS},
+        test_utils::{metrics, node_packet_with_ttl, random_node_packet},
+        util::PublicKeyBytes,
+    };
+
+    /// A slow upstream without packets, which counts its lookups.
+    #[derive(Debug, Clone, Default)]
+    struct SlowUpstream {
+        lookups: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl UpstreamResolver for SlowUpstream {
+        async fn resolve(&self, _pubkey: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_from_upstream() -> Result<()> {
+        let dht = MockDht::new();
+        let store = ZoneStore::in_memory()?.with_upstream(dht.clone());
+
+        let (_secret_key, packet) = random_node_packet()?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+        let name = Name::from_utf8(IROH_TXT_NAME)?;
+
+        // Not found anywhere.
+        assert!(store
+            .resolve(&pubkey, &name, RecordType::TXT)
+            .await?
+            .is_none());
+        assert!(store.get_signed_packet(&pubkey).await?.is_none());
+
+        // Published only to the DHT, found after the negative cache entry was cleared.
+        dht.publish(&packet)?;
+        store.remove(&pubkey).await?;
+        assert!(store
+            .resolve(&pubkey, &name, RecordType::TXT)
+            .await?
+            .is_some());
+        // The packet is stored locally.
+        assert!(store.get_signed_packet(&pubkey).await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revalidate_upstream_packets() -> Result<()> {
+        let metrics = metrics();
+        let dht = MockDht::new();
+        let store = ZoneStore::in_memory()?.with_upstream(dht.clone());
+        let name = Name::from_utf8(IROH_TXT_NAME)?;
+
+        let secret_key = SecretKey::generate();
+        let v1 = node_packet_with_ttl(&secret_key, 1)?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&v1);
+        dht.publish(&v1)?;
+        assert!(store
+            .resolve(&pubkey, &name, RecordType::TXT)
+            .await?
+            .is_some());
+
+        // A newer packet is published upstream only.
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let v2 = node_packet_with_ttl(&secret_key, 1)?;
+        dht.publish(&v2)?;
+
+        // Before its TTL passed, the resolved packet is served without asking upstream.
+        let revalidations = metrics.upstream_revalidations.get();
+        store.resolve(&pubkey, &name, RecordType::TXT).await?;
+        let stored = store
+            .get_signed_packet(&pubkey)
+            .await?
+            .expect("packet is stored");
+        assert_eq!(stored.timestamp(), v1.timestamp());
+
+        // Afterwards, lookups trigger a revalidation which stores the newer packet.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                store.resolve(&pubkey, &name, RecordType::TXT).await?;
+                let stored = store.get_signed_packet(&pubkey).await?;
+                if stored.is_some_and(|p| p.timestamp() == v2.timestamp()) {
+                    break anyhow::Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await??;
+        assert!(metrics.upstream_revalidations.get() > revalidations);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn republish() -> Result<()> {
+        let dht = MockDht::new();
+        let store = ZoneStore::in_memory()?;
+
+        let (_secret_key, packet) = random_node_packet()?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+        store.insert(packet, PacketSource::PkarrPublish).await?;
+
+        // Packets older than the max age are not republished.
+        let (published, failed) = store
+            .republish(Arc::new(dht.clone()), Duration::ZERO)
+            .await?;
+        assert_eq!((published, failed), (0, 0));
+        assert!(dht.get(&pubkey)?.is_none());
+
+        let (published, failed) = store
+            .republish(Arc::new(dht.clone()), Duration::from_secs(60))
+            .await?;
+        assert_eq!((published, failed), (1, 0));
+        assert!(dht.get(&pubkey)?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn single_upstream_lookup_per_pubkey() -> Result<()> {
+        let upstream = SlowUpstream::default();
+        let store = ZoneStore::in_memory()?.with_upstream(upstream.clone());
+        let (_secret_key, packet) = random_node_packet()?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+
+        let lookups = (0..8).map(|_| store.resolve_signed_packet(&pubkey));
+        for packet in futures::future::join_all(lookups).await {
+            assert!(packet?.is_none());
+        }
+        assert_eq!(upstream.lookups.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limit_concurrent_upstream_lookups() -> Result<()> {
+        let upstream = SlowUpstream::default();
+        let store = ZoneStore::in_memory()?.with_upstream(upstream.clone());
+        let pubkeys = (0..MAX_CONCURRENT_UPSTREAM_LOOKUPS + 1)
+            .map(|_| Ok(PublicKeyBytes::from_signed_packet(&random_node_packet()?.1)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let lookups = pubkeys
+            .iter()
+            .map(|pubkey| store.resolve_signed_packet(pubkey));
+        let results = futures::future::join_all(lookups).await;
+        let failed = results.iter().filter(|res| res.is_err()).count();
+        assert_eq!(failed, 1);
+        assert_eq!(
+            upstream.lookups.load(Ordering::SeqCst),
+            MAX_CONCURRENT_UPSTREAM_LOOKUPS
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use bytes::Bytes;
+use iroh_metrics::inc;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+use url::Url;
+
+use super::RelayClient;
+use crate::{
+    metrics::{Metrics, UpstreamMetrics},
+    util::PublicKeyBytes,
+};
+
+/// Retry a failed forward up to 5 times by default
+pub const DEFAULT_FORWARD_MAX_RETRIES: u32 = 5;
+
+/// Wait 1 second before the first retry by default
+pub const DEFAULT_FORWARD_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Wait at most 1 minute between retries
+const MAX_FORWARD_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Keep at most 1024 forwards in flight, further forwards are dropped
+const MAX_PENDING_FORWARDS: usize = 1024;
+
+/// Options for a [`RelayForwarder`].
+#[derive(Debug, Clone)]
+pub struct ForwardOptions {
+    /// URLs of the pkarr relays to forward to, e.g. `https://relay.example/pkarr`.
+    pub relays: Vec<Url>,
+    /// Number of retries after a failed forward.
+    pub max_retries: u32,
+    /// Time to wait before the first retry. The backoff doubles with each retry.
+    pub initial_backoff: Duration,
+}
+
+impl Default for ForwardOptions {
+    fn default() -> Self {
+        Self {
+            relays: vec![],
+            max_retries: DEFAULT_FORWARD_MAX_RETRIES,
+            initial_backoff: DEFAULT_FORWARD_INITIAL_BACKOFF,
+        }
+    }
+}
+
+/// Forwards published packets to other pkarr relays in the background.
+#[derive(Debug, Clone)]
+pub struct RelayForwarder {
+    relays: Arc<Vec<RelayClient>>,
+    max_retries: u32,
+    initial_backoff: Duration,
+    permits: Arc<Semaphore>,
+}
+
+impl RelayForwarder {
+    /// Create a new forwarder.
+    pub fn new(options: ForwardOptions) -> Result<Self> {
+        let relays = options
+            .relays
+            .into_iter()
+            .map(RelayClient::new)
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            relays: Arc::new(relays),
+            max_retries: options.max_retries,
+            initial_backoff: options.initial_backoff,
+            permits: Arc::new(Semaphore::new(MAX_PENDING_FORWARDS)),
+        })
+    }
+
+    /// Forward a signed packet in the relay format to all relays.
+    ///
+    /// This returns immediately, the packet is sent and retried on failure in the background.
+    pub fn forward(&self, pubkey: &PublicKeyBytes, body: Bytes) {
+        for relay in self.relays.iter() {
+            let Ok(permit) = self.permits.clone().try_acquire_owned() else {
+                warn!(relay = %relay.url(), %pubkey, "too many pending forwards, dropping");
+                record_failure(relay);
+                continue;
+            };
+            let this = self.clone();
+            let relay = relay.clone();
+            let pubkey = pubkey.clone();
+            let body = body.clone();
+            tokio::task::spawn(async move {
+                this.forward_with_retries(&relay, &pubkey, body).await;
+                drop(permit);
+            });
+        }
+    }
+
+    async fn forward_with_retries(
+        &self,
+        relay: &RelayClient,
+        pubkey: &PublicKeyBytes,
+        body: Bytes,
+    ) {
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match relay.put(pubkey, body.clone()).await {
+                Ok(()) => {
+                    debug!(relay = %relay.url(), %pubkey, "forwarded packet");
+                    inc!(Metrics, relay_forward_success);
+                    UpstreamMetrics::get().forward_success(relay.url());
+                    return;
+                }
+                Err(err) if retries < self.max_retries => {
+                    debug!(relay = %relay.url(), %pubkey, ?err, ?backoff, "forward failed, retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_FORWARD_BACKOFF);
+                    retries += 1;
+                }
+                Err(err) => {
+                    warn!(relay = %relay.url(), %pubkey, ?err, "forward failed, giving up");
+                    record_failure(relay);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn record_failure(relay: &RelayClient) {
+    inc!(Metrics, relay_forward_error);
+    UpstreamMetrics::get().forward_error(relay.url());
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use pkarr::{PkarrClient, SignedPacket};
+
+use super::{UpstreamPublisher, UpstreamResolver};
+use crate::util::PublicKeyBytes;
+
+/// A client for the mainline DHT, resolving and publishing pkarr packets stored as
+/// [BEP44](https://www.bittorrent.org/beps/bep_0044.html) mutable items.
+#[derive(derive_more::Debug, Clone)]
+pub struct MainlineClient {
+    #[debug("PkarrClient")]
+    client: Arc<PkarrClient>,
+}
+
+impl MainlineClient {
+    /// Create a new client and join the DHT.
+    ///
+    /// If `bootstrap` is `None`, the default public bootstrap nodes are used.
+    pub fn new(bootstrap: Option<Vec<String>>) -> Result<Self> {
+        let mut builder = PkarrClient::builder();
+        if let Some(bootstrap) = bootstrap {
+            builder = builder.bootstrap(&bootstrap);
+        }
+        let client = builder.build();
+        Ok(Self {
+            client: Arc::new(client),
+        })
+    }
+}
+
+#[async_trait]
+impl UpstreamResolver for MainlineClient {
+    async fn resolve(&self, pubkey: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        let pubkey = pkarr::PublicKey::try_from(pubkey.clone())?;
+        // The pkarr client verifies the signatures of the packets it receives.
+        let packet = self.client.resolve(pubkey).await;
+        Ok(packet)
+    }
+}
+
+#[async_trait]
+impl UpstreamPublisher for MainlineClient {
+    async fn publish(&self, packet: &SignedPacket) -> Result<()> {
+        self.client.publish(packet).await?;
+        Ok(())
+    }
+}
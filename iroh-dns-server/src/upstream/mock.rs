@@ -0,0 +1,65 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use pkarr::SignedPacket;
+
+use super::{UpstreamPublisher, UpstreamResolver};
+use crate::util::PublicKeyBytes;
+
+/// An in-process stand-in for the mainline DHT.
+///
+/// Clones share the same packets. This is useful to test upstream resolution without network
+/// access.
+#[derive(Debug, Clone, Default)]
+pub struct MockDht {
+    packets: Arc<Mutex<BTreeMap<PublicKeyBytes, Bytes>>>,
+}
+
+impl MockDht {
+    /// Create a new, empty mock DHT.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publish a packet, unless a more recent packet for its pubkey was published before.
+    ///
+    /// Returns whether the packet was stored.
+    pub fn publish(&self, packet: &SignedPacket) -> Result<bool> {
+        let key = PublicKeyBytes::from_signed_packet(packet);
+        let mut packets = self.packets.lock();
+        if let Some(existing) = packets.get(&key) {
+            let existing = SignedPacket::from_bytes(existing.clone(), false)?;
+            if existing.more_recent_than(packet) {
+                return Ok(false);
+            }
+        }
+        packets.insert(key, Bytes::copy_from_slice(&packet.as_bytes()[..]));
+        Ok(true)
+    }
+
+    /// Get the published packet for a pubkey.
+    pub fn get(&self, pubkey: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        let bytes = self.packets.lock().get(pubkey).cloned();
+        bytes
+            .map(|bytes| SignedPacket::from_bytes(bytes, false).map_err(anyhow::Error::from))
+            .transpose()
+    }
+}
+
+#[async_trait]
+impl UpstreamResolver for MockDht {
+    async fn resolve(&self, pubkey: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        self.get(pubkey)
+    }
+}
+
+#[async_trait]
+impl UpstreamPublisher for MockDht {
+    async fn publish(&self, packet: &SignedPacket) -> Result<()> {
+        MockDht::publish(self, packet)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use pkarr::SignedPacket;
+use url::Url;
+
+use super::{UpstreamPublisher, UpstreamResolver};
+use crate::{store::PacketSource, util::PublicKeyBytes};
+
+/// Timeout for requests to the relay.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A client for another pkarr relay.
+#[derive(Debug, Clone)]
+pub struct RelayClient {
+    url: Url,
+    http: reqwest::Client,
+}
+
+impl RelayClient {
+    /// Create a client for the relay at `url`, e.g. `https://relay.example/pkarr`.
+    pub fn new(url: Url) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Self { url, http })
+    }
+
+    /// The URL of the relay.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Publish a signed packet in the relay format.
+    ///
+    /// Succeeds as well if the relay already stores a more recent packet for the pubkey.
+    pub async fn put(&self, pubkey: &PublicKeyBytes, body: Bytes) -> Result<()> {
+        let res = self
+            .http
+            .put(self.key_url(pubkey)?)
+            .body(body)
+            .send()
+            .await?;
+        let status = res.status();
+        if status == reqwest::StatusCode::CONFLICT {
+            return Ok(());
+        }
+        ensure!(status.is_success(), "relay responded with {status}");
+        Ok(())
+    }
+
+    /// Get the signed packet for a pubkey.
+    ///
+    /// The signature of the packet is verified.
+    pub async fn get(&self, pubkey: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        let res = self.http.get(self.key_url(pubkey)?).send().await?;
+        let status = res.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        ensure!(status.is_success(), "relay responded with {status}");
+        let body = res.bytes().await?;
+        let public_key = pkarr::PublicKey::try_from(pubkey.clone())?;
+        let packet = SignedPacket::from_relay_response(public_key, body)?;
+        Ok(Some(packet))
+    }
+
+    fn key_url(&self, pubkey: &PublicKeyBytes) -> Result<Url> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid relay url"))?
+            .pop_if_empty()
+            .push(&pubkey.to_z32());
+        Ok(url)
+    }
+}
+
+#[async_trait]
+impl UpstreamPublisher for RelayClient {
+    async fn publish(&self, packet: &SignedPacket) -> Result<()> {
+        let pubkey = PublicKeyBytes::from_signed_packet(packet);
+        self.put(&pubkey, packet.as_relay_request()).await
+    }
+}
+
+#[async_trait]
+impl UpstreamResolver for RelayClient {
+    async fn resolve(&self, pubkey: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        self.get(pubkey).await
+    }
+
+    fn source(&self) -> PacketSource {
+        PacketSource::RelayProxy
+    }
+}
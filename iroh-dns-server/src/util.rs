@@ -16,28 +16,34 @@ use hickory_proto::{
 };
 use pkarr::SignedPacket;
 
-#[derive(derive_more::From, derive_more::Into, Eq, PartialEq, Ord, PartialOrd, Hash)]
+/// The 32 bytes of an ed25519 public key, used as the key of a pkarr zone.
+#[derive(derive_more::From, derive_more::Into, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct PublicKeyBytes([u8; 32]);
 
 impl PublicKeyBytes {
+    /// Parse from a z-base-32 encoded string.
     pub fn from_z32(s: &str) -> Result<Self> {
         let bytes = z32::decode(s.as_bytes())?;
         let bytes: [u8; 32] = bytes.try_into().map_err(|_| anyhow!("invalid length"))?;
         Ok(Self(bytes))
     }
 
+    /// Encode as z-base-32.
     pub fn to_z32(&self) -> String {
         z32::encode(&self.0)
     }
 
+    /// Get the key bytes.
     pub fn to_bytes(&self) -> [u8; 32] {
         self.0
     }
 
+    /// Get a reference to the key bytes.
     pub fn as_bytes(&self) -> &[u8; 32] {
         &self.0
     }
 
+    /// Get the public key of a signed packet.
     pub fn from_signed_packet(packet: &SignedPacket) -> Self {
         Self(packet.public_key().to_bytes())
     }
@@ -87,12 +93,15 @@ pub fn signed_packet_to_hickory_message(signed_packet: &SignedPacket) -> Result<
     Ok(message)
 }
 
-pub fn signed_packet_to_hickory_records_without_origin(
-    signed_packet: &SignedPacket,
+/// Get the records of the zone of `pubkey` from an encoded DNS packet, with names relative to
+/// the zone.
+pub fn encoded_packet_to_hickory_records_without_origin(
+    pubkey: &PublicKeyBytes,
+    encoded_packet: &[u8],
     filter: impl Fn(&Record) -> bool,
 ) -> Result<(Label, BTreeMap<RrKey, Arc<RecordSet>>)> {
-    let common_zone = Label::from_utf8(&signed_packet.public_key().to_z32())?;
-    let mut message = signed_packet_to_hickory_message(signed_packet)?;
+    let common_zone = Label::from_utf8(&pubkey.to_z32())?;
+    let mut message = Message::from_bytes(encoded_packet)?;
     let answers = message.take_answers();
     let mut output: BTreeMap<RrKey, Arc<RecordSet>> = BTreeMap::new();
     for mut record in answers.into_iter() {
@@ -105,7 +114,7 @@ pub fn signed_packet_to_hickory_records_without_origin(
         if name.num_labels() < 1 {
             continue;
         }
-        let zone = name.iter().last().unwrap().into_label()?;
+        let zone = name.iter().next_back().unwrap().into_label()?;
         if zone != common_zone {
             continue;
         }